@@ -0,0 +1,62 @@
+use std::env;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rcore_thread::scheduler::{LockFreeRRScheduler, RRScheduler, Scheduler};
+
+const OPS_PER_THREAD: usize = 200_000;
+/// Ready thread ids per benchmark thread.
+const TIDS_PER_THREAD: usize = 4;
+
+fn main() {
+    let max_threads = env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse().ok())
+        .unwrap_or(8);
+    println!("{:>8} {:>12} {:>12}", "threads", "rr", "lock-free");
+    let mut n = 1;
+    while n <= max_threads {
+        let tid_num = n * TIDS_PER_THREAD;
+        let rr = bench(n, RRScheduler::new(5), tid_num);
+        let lock_free = bench(n, LockFreeRRScheduler::new(5, tid_num), tid_num);
+        println!("{:>8} {:>12} {:>12}", n, mops(n, rr), mops(n, lock_free));
+        n *= 2;
+    }
+}
+
+/// Run `n` threads popping a thread from `scheduler` and pushing it back,
+/// return the elapsed time.
+fn bench<S: Scheduler + Send + Sync>(n: usize, scheduler: S, tid_num: usize) -> Duration {
+    for tid in 0..tid_num {
+        scheduler.push(tid);
+    }
+    let scheduler = Arc::new(scheduler);
+    let barrier = Arc::new(Barrier::new(n + 1));
+    let handles: Vec<_> = (0..n)
+        .map(|cpu| {
+            let scheduler = scheduler.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..OPS_PER_THREAD {
+                    if let Some(tid) = scheduler.pop(cpu) {
+                        scheduler.push(tid);
+                    }
+                }
+            })
+        })
+        .collect();
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+/// Million push and pop pairs per second, as text.
+fn mops(n: usize, elapsed: Duration) -> String {
+    let mops = (n * OPS_PER_THREAD) as f64 / elapsed.as_secs_f64() / 1e6;
+    format!("{:.2} M/s", mops)
+}
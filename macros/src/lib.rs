@@ -0,0 +1,75 @@
+//! Procedural macros of `rcore-thread`.
+
+extern crate proc_macro;
+
+use proc_macro::{Delimiter, Group, Ident, Literal, Punct, Spacing, Span, TokenStream, TokenTree};
+
+/// Record an `async fn` in the await tree of its task under its own name,
+/// as if its body was wrapped in `instrument_await!`.
+#[proc_macro_attribute]
+pub fn traced(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return compile_error("#[traced] takes no arguments");
+    }
+    let mut tokens: Vec<TokenTree> = item.into_iter().collect();
+    let is_async = tokens
+        .iter()
+        .any(|t| matches!(t, TokenTree::Ident(i) if i.to_string() == "async"));
+    let name = tokens
+        .iter()
+        .skip_while(|t| !matches!(t, TokenTree::Ident(i) if i.to_string() == "fn"))
+        .nth(1)
+        .map(|t| t.to_string());
+    let body = tokens
+        .iter()
+        .rposition(|t| matches!(t, TokenTree::Group(g) if g.delimiter() == Delimiter::Brace));
+    let (name, body) = match (is_async, name, body) {
+        (true, Some(name), Some(body)) => (name, body),
+        _ => return compile_error("#[traced] only applies to an async fn"),
+    };
+
+    // { ::rcore_thread::instrument_await!("name", async move BODY).await }
+    let mut args = TokenStream::new();
+    args.extend(vec![
+        TokenTree::Literal(Literal::string(&name)),
+        TokenTree::Punct(Punct::new(',', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("async", Span::call_site())),
+        TokenTree::Ident(Ident::new("move", Span::call_site())),
+        tokens[body].clone(),
+    ]);
+    let mut wrapped = path(&["rcore_thread", "instrument_await"]);
+    wrapped.extend(vec![
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(Group::new(Delimiter::Parenthesis, args)),
+        TokenTree::Punct(Punct::new('.', Spacing::Alone)),
+        TokenTree::Ident(Ident::new("await", Span::call_site())),
+    ]);
+    tokens[body] = TokenTree::Group(Group::new(Delimiter::Brace, wrapped));
+    tokens.into_iter().collect()
+}
+
+/// `::a::b::c`
+fn path(segments: &[&str]) -> TokenStream {
+    let mut stream = TokenStream::new();
+    for segment in segments {
+        stream.extend(vec![
+            TokenTree::Punct(Punct::new(':', Spacing::Joint)),
+            TokenTree::Punct(Punct::new(':', Spacing::Alone)),
+            TokenTree::Ident(Ident::new(segment, Span::call_site())),
+        ]);
+    }
+    stream
+}
+
+fn compile_error(msg: &str) -> TokenStream {
+    let mut stream = path(&["core", "compile_error"]);
+    stream.extend(vec![
+        TokenTree::Punct(Punct::new('!', Spacing::Alone)),
+        TokenTree::Group(Group::new(
+            Delimiter::Parenthesis,
+            TokenTree::Literal(Literal::string(msg)).into(),
+        )),
+        TokenTree::Punct(Punct::new(';', Spacing::Alone)),
+    ]);
+    stream
+}
@@ -0,0 +1,188 @@
+//! Await-tree diagnostics
+//!
+//! Futures wrapped with [`instrument_await!`](crate::instrument_await) or in a
+//! [`#[traced]`](crate::traced) async fn record themselves in the tree of their task
+//! while they are pending. Each poll of the task rebuilds the tree, so at any time it
+//! shows the `.await` points the task is parked on. It is printed by the executor dump.
+
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use lazy_static::*;
+use spin::Mutex;
+
+use crate::platform;
+
+/// CPUs beyond this are not traced.
+pub(crate) const MAX_CPUS: usize = 64;
+
+lazy_static! {
+    /// The tree of the task being polled on each CPU.
+    static ref CURRENT: Vec<AtomicPtr<AwaitTree>> =
+        (0..MAX_CPUS).map(|_| AtomicPtr::new(ptr::null_mut())).collect();
+}
+
+struct Span {
+    depth: usize,
+    label: &'static str,
+}
+
+/// The pending await points of one task, in pre-order.
+#[derive(Default)]
+pub struct AwaitTree {
+    spans: Mutex<Vec<Span>>,
+    /// Nesting depth of the span being polled.
+    depth: AtomicUsize,
+}
+
+impl AwaitTree {
+    pub fn new() -> Self {
+        AwaitTree::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.lock().is_empty()
+    }
+
+    /// Print the tree, one span per line, indented by depth after `indent` spaces.
+    pub fn dump(&self, w: &mut dyn Write, indent: usize) -> fmt::Result {
+        for span in self.spans.lock().iter() {
+            let pad = indent + 2 * span.depth;
+            writeln!(w, "{:pad$}- {}", "", span.label, pad = pad)?;
+        }
+        Ok(())
+    }
+
+    fn enter(&self, label: &'static str) -> usize {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed);
+        let mut spans = self.spans.lock();
+        spans.push(Span { depth, label });
+        spans.len() - 1
+    }
+
+    /// Leave the span at `index`. A ready span is removed along with its children.
+    fn exit(&self, index: usize, ready: bool) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        if ready {
+            self.spans.lock().truncate(index);
+        }
+    }
+}
+
+/// Makes `tree` the current tree of this CPU until dropped.
+pub(crate) struct PollGuard {
+    cpu: usize,
+}
+
+impl PollGuard {
+    /// Start a poll of the task owning `tree`, clearing its previous spans.
+    pub(crate) fn enter(tree: &AwaitTree) -> Option<Self> {
+        let cpu = platform::cpu_id();
+        let slot = CURRENT.get(cpu)?;
+        tree.spans.lock().clear();
+        tree.depth.store(0, Ordering::Relaxed);
+        slot.store(tree as *const _ as *mut _, Ordering::Release);
+        Some(PollGuard { cpu })
+    }
+}
+
+impl Drop for PollGuard {
+    fn drop(&mut self) {
+        CURRENT[self.cpu].store(ptr::null_mut(), Ordering::Release);
+    }
+}
+
+fn current() -> Option<&'static AwaitTree> {
+    let ptr = CURRENT.get(platform::cpu_id())?.load(Ordering::Acquire);
+    // the tree outlives the poll, and we only get here from inside the poll
+    unsafe { ptr.as_ref() }
+}
+
+/// A future recording itself as `label` in the await tree of its task.
+pub struct Instrumented<F> {
+    label: &'static str,
+    fut: F,
+}
+
+impl<F: Future> Instrumented<F> {
+    pub fn new(label: &'static str, fut: F) -> Self {
+        Instrumented { label, fut }
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<F::Output> {
+        let label = self.label;
+        // safety: `fut` is structurally pinned and never moved
+        let fut = unsafe { self.map_unchecked_mut(|s| &mut s.fut) };
+        let tree = match current() {
+            Some(tree) => tree,
+            None => return fut.poll(cx),
+        };
+        let index = tree.enter(label);
+        let ret = fut.poll(cx);
+        tree.exit(index, ret.is_ready());
+        ret
+    }
+}
+
+/// Record `fut` as `label` in the await tree of the current task while it is pending.
+///
+/// ```ignore
+/// let block = instrument_await!("virtio-blk read", dev.read(sector)).await;
+/// ```
+#[macro_export]
+macro_rules! instrument_await {
+    ($label:expr, $fut:expr) => {
+        $crate::asynchronous::await_tree::Instrumented::new($label, $fut)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asynchronous::executor::{tests::CPU0, Executor};
+    use crate::traced;
+    use alloc::{boxed::Box, string::String, sync::Arc};
+    use core::sync::atomic::AtomicBool;
+
+    /// Pending until `open` is set.
+    struct Gate(Arc<AtomicBool>);
+
+    impl Future for Gate {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0.load(Ordering::Relaxed) {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[traced]
+    async fn read_block(gate: Arc<AtomicBool>) {
+        crate::instrument_await!("wait irq", Gate(gate)).await
+    }
+
+    #[test]
+    fn tree_shows_pending_await_points() {
+        let _cpu = CPU0.lock();
+        let ex: &'static Executor = Box::leak(Box::new(Executor::new()));
+        let gate = Arc::new(AtomicBool::new(false));
+        let handle = ex.spawn(read_block(gate.clone()));
+        assert!(ex.run_once());
+        let mut out = String::new();
+        handle.info().await_tree().dump(&mut out, 0).unwrap();
+        assert_eq!(out, "- read_block\n  - wait irq\n");
+        gate.store(true, Ordering::Relaxed);
+        assert!(ex.run_once());
+        assert!(handle.info().await_tree().is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+//! Bridges between async tasks and kernel threads
+//!
+//! The thread pool lives with the kernel, and is reached through a [`ThreadHost`]
+//! registered once at boot with [`set_thread_host`].
+
+use crate::scheduler::Scheduler;
+use alloc::{boxed::Box, sync::Arc};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use spin::{Mutex, Once};
+
+/// What the kernel thread pool provides to the async world.
+pub trait ThreadHost: Sync {
+    /// Run `f` on a kernel thread from the thread pool.
+    fn spawn(&self, f: Box<dyn FnOnce() + Send>);
+    /// Id of the running kernel thread.
+    fn current_tid(&self) -> usize;
+    /// Switch away from the running thread without putting it back to the ready queue.
+    ///
+    /// The thread may be pushed to the scheduler again before it actually switched away,
+    /// in which case it must simply keep running.
+    fn park(&self);
+    /// The scheduler that parked threads are pushed back to when woken.
+    fn scheduler(&self) -> &dyn Scheduler;
+}
+
+static THREAD_HOST: Once<&'static dyn ThreadHost> = Once::new();
+
+/// Register the kernel thread pool. Only the first call takes effect.
+pub fn set_thread_host(host: &'static dyn ThreadHost) {
+    THREAD_HOST.call_once(|| host);
+}
+
+fn thread_host() -> &'static dyn ThreadHost {
+    *THREAD_HOST
+        .r#try()
+        .expect("no thread host, call set_thread_host first")
+}
+
+struct BlockingState<R> {
+    result: Option<R>,
+    waker: Option<Waker>,
+}
+
+/// Future of a closure running on a kernel thread, returned by [`spawn_blocking`].
+pub struct BlockingTask<R> {
+    state: Arc<Mutex<BlockingState<R>>>,
+}
+
+/// Run the blocking closure `f` on a kernel thread from the thread pool.
+pub fn spawn_blocking<F, R>(f: F) -> BlockingTask<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let state = Arc::new(Mutex::new(BlockingState {
+        result: None,
+        waker: None,
+    }));
+    let thread_state = state.clone();
+    thread_host().spawn(Box::new(move || {
+        let ret = f();
+        let waker = {
+            let mut state = thread_state.lock();
+            state.result = Some(ret);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }));
+    BlockingTask { state }
+}
+
+impl<R> Future for BlockingTask<R> {
+    type Output = R;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<R> {
+        let mut state = self.state.lock();
+        match state.result.take() {
+            Some(ret) => Poll::Ready(ret),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+const EMPTY: u8 = 0;
+const PARKED: u8 = 1;
+const NOTIFIED: u8 = 2;
+
+/// Wakes a kernel thread blocked in [`block_on`].
+struct Parker {
+    tid: usize,
+    state: AtomicU8,
+    host: &'static dyn ThreadHost,
+}
+
+impl Parker {
+    fn park(&self) {
+        if self
+            .state
+            .compare_exchange(EMPTY, PARKED, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            // woken threads always come back with NOTIFIED
+            while self.state.load(Ordering::Acquire) == PARKED {
+                self.host.park();
+            }
+        }
+        self.state.store(EMPTY, Ordering::Release);
+    }
+
+    fn unpark(&self) {
+        if self.state.swap(NOTIFIED, Ordering::AcqRel) == PARKED {
+            self.host.scheduler().push(self.tid);
+        }
+    }
+}
+
+static PARKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(parker_clone, parker_wake, parker_wake_by_ref, parker_drop);
+
+unsafe fn parker_clone(ptr: *const ()) -> RawWaker {
+    let parker = Arc::from_raw(ptr as *const Parker);
+    core::mem::forget(parker.clone());
+    RawWaker::new(Arc::into_raw(parker) as *const (), &PARKER_VTABLE)
+}
+
+unsafe fn parker_wake(ptr: *const ()) {
+    let parker = Arc::from_raw(ptr as *const Parker);
+    parker.unpark();
+}
+
+unsafe fn parker_wake_by_ref(ptr: *const ()) {
+    (*(ptr as *const Parker)).unpark();
+}
+
+unsafe fn parker_drop(ptr: *const ()) {
+    drop(Arc::from_raw(ptr as *const Parker));
+}
+
+/// Block the current kernel thread until `fut` completes.
+///
+/// Between polls the thread is parked, and pushed back to the scheduler when woken.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let host = thread_host();
+    let parker = Arc::new(Parker {
+        tid: host.current_tid(),
+        state: AtomicU8::new(EMPTY),
+        host,
+    });
+    let raw = RawWaker::new(Arc::into_raw(parker.clone()) as *const (), &PARKER_VTABLE);
+    let waker = unsafe { Waker::from_raw(raw) };
+    let mut cx = Context::from_waker(&waker);
+    let mut fut = fut;
+    // safety: `fut` is shadowed and never moved again
+    let mut fut = unsafe { Pin::new_unchecked(&mut fut) };
+    loop {
+        if let Poll::Ready(ret) = fut.as_mut().poll(&mut cx) {
+            return ret;
+        }
+        parker.park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::RRScheduler;
+    use alloc::boxed::Box;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Runs closures on std threads, and parks by yielding.
+    struct StdHost {
+        scheduler: RRScheduler,
+    }
+
+    impl ThreadHost for StdHost {
+        fn spawn(&self, f: Box<dyn FnOnce() + Send>) {
+            thread::spawn(f);
+        }
+        fn current_tid(&self) -> usize {
+            0
+        }
+        fn park(&self) {
+            thread::yield_now();
+        }
+        fn scheduler(&self) -> &dyn Scheduler {
+            &self.scheduler
+        }
+    }
+
+    fn host() -> &'static StdHost {
+        Box::leak(Box::new(StdHost {
+            scheduler: RRScheduler::new(1),
+        }))
+    }
+
+    #[test]
+    fn block_on_blocking_task() {
+        let host = host();
+        set_thread_host(host);
+        let ret = block_on(spawn_blocking(|| {
+            thread::sleep(Duration::from_millis(10));
+            42
+        }));
+        assert_eq!(ret, 42);
+    }
+
+    #[test]
+    fn unpark_pushes_only_parked_threads() {
+        let parker = Arc::new(Parker {
+            tid: 3,
+            state: AtomicU8::new(EMPTY),
+            host: host(),
+        });
+        // woken before parking: the next park returns at once
+        parker.unpark();
+        parker.park();
+        assert_eq!(parker.state.load(Ordering::Relaxed), EMPTY);
+
+        let waker = parker.clone();
+        let thread = thread::spawn(move || {
+            while waker.state.load(Ordering::Acquire) != PARKED {
+                thread::yield_now();
+            }
+            waker.unpark();
+        });
+        parker.park();
+        thread.join().unwrap();
+        // pushed only for the wake while parked
+        assert_eq!(parker.host.scheduler().pop(0), Some(3));
+        assert_eq!(parker.host.scheduler().pop(0), None);
+    }
+}
@@ -0,0 +1,399 @@
+//! Submission/completion queue pairs
+//!
+//! Queue-pair devices such as virtio-blk and NVMe take requests tagged with an id,
+//! and post completions with the same id, possibly out of order, from the interrupt handler.
+//! [`CompletionQueue`] hands out the ids, bounds the number of requests in flight,
+//! and resolves each request's future when its completion is posted.
+//!
+//! Tasks run with interrupts disabled, so the interrupt handler never spins on a lock
+//! held by a task of the same CPU.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use log::*;
+use spin::Mutex;
+
+enum Slot<T> {
+    Free,
+    /// Reserved, but the request is not posted to the device yet.
+    Reserved,
+    /// Posted to the device, with the waker of the waiting task.
+    InFlight(Option<Waker>),
+    /// Completed, waiting for the task to take the result.
+    Completed(T),
+    /// The task is gone. The slot is reused once the device posts the completion.
+    Cancelled,
+}
+
+pub struct CompletionQueue<T> {
+    inner: Mutex<Inner<T>>,
+}
+
+struct Inner<T> {
+    slots: Vec<Slot<T>>,
+    /// Free slots, only while no task is waiting.
+    free: Vec<usize>,
+    /// Tasks waiting for a free slot, in arrival order.
+    waiters: BTreeMap<u64, Waker>,
+    /// Slots handed to woken waiters which have not taken them yet.
+    granted: BTreeMap<u64, usize>,
+    next_ticket: u64,
+}
+
+impl<T> Inner<T> {
+    /// Hand slot `id` to the first waiter, returning its waker, or free it if none waits.
+    fn release(&mut self, id: usize) -> Option<Waker> {
+        let ticket = self.waiters.keys().next().copied();
+        match ticket {
+            Some(ticket) => {
+                self.slots[id] = Slot::Reserved;
+                self.granted.insert(ticket, id);
+                self.waiters.remove(&ticket)
+            }
+            None => {
+                self.slots[id] = Slot::Free;
+                self.free.push(id);
+                None
+            }
+        }
+    }
+}
+
+impl<T> CompletionQueue<T> {
+    /// Create a queue with at most `depth` requests in flight.
+    pub fn new(depth: usize) -> Self {
+        let mut slots = Vec::with_capacity(depth);
+        slots.resize_with(depth, || Slot::Free);
+        CompletionQueue {
+            inner: Mutex::new(Inner {
+                slots,
+                free: (0..depth).rev().collect(),
+                waiters: BTreeMap::new(),
+                granted: BTreeMap::new(),
+                next_ticket: 0,
+            }),
+        }
+    }
+
+    /// Maximum number of requests in flight.
+    pub fn depth(&self) -> usize {
+        self.inner.lock().slots.len()
+    }
+
+    /// Number of slots taken, including cancelled requests still owned by the device.
+    pub fn in_flight(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.slots.len() - inner.free.len()
+    }
+
+    /// Wait for a free slot.
+    pub fn reserve(&self) -> Reserve<'_, T> {
+        Reserve {
+            queue: self,
+            ticket: None,
+        }
+    }
+
+    /// Reserve a slot, post the request with `post(id)`, and wait for its completion.
+    pub async fn submit<F: FnOnce(usize)>(&self, post: F) -> T {
+        let submission = self.reserve().await;
+        post(submission.id());
+        submission.submit().await
+    }
+
+    /// Post the completion of request `id`. Called from the interrupt handler.
+    ///
+    /// Return false if no request with this id is in flight.
+    pub fn complete(&self, id: usize, value: T) -> bool {
+        let (waker, dropped) = {
+            let mut inner = self.inner.lock();
+            match inner.slots.get_mut(id) {
+                Some(slot @ Slot::InFlight(_)) => {
+                    let waker = match core::mem::replace(slot, Slot::Completed(value)) {
+                        Slot::InFlight(waker) => waker,
+                        _ => unreachable!(),
+                    };
+                    (waker, None)
+                }
+                Some(Slot::Cancelled) => (inner.release(id), Some(value)),
+                _ => {
+                    warn!("completion queue: unexpected completion {}", id);
+                    return false;
+                }
+            }
+        };
+        // drop the value of a cancelled request outside the lock
+        drop(dropped);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        true
+    }
+
+    /// Free the slot of a cancelled request that the driver aborted on the device,
+    /// so no completion will ever be posted for it.
+    ///
+    /// Return false if request `id` is not cancelled.
+    pub fn reclaim(&self, id: usize) -> bool {
+        let waker = {
+            let mut inner = self.inner.lock();
+            match inner.slots.get(id) {
+                Some(Slot::Cancelled) => inner.release(id),
+                _ => return false,
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        true
+    }
+
+    /// Ids of the cancelled requests still owned by the device.
+    pub fn cancelled(&self) -> Vec<usize> {
+        let inner = self.inner.lock();
+        let iter = inner.slots.iter().enumerate();
+        iter.filter(|(_, slot)| matches!(slot, Slot::Cancelled))
+            .map(|(id, _)| id)
+            .collect()
+    }
+}
+
+/// Future of a free slot, returned by [`CompletionQueue::reserve`].
+pub struct Reserve<'a, T> {
+    queue: &'a CompletionQueue<T>,
+    ticket: Option<u64>,
+}
+
+impl<'a, T> Future for Reserve<'a, T> {
+    type Output = Submission<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let queue = self.queue;
+        let mut inner = queue.inner.lock();
+        // first come, first served: a waiter only gets a slot handed over by `release`
+        let id = match self.ticket {
+            Some(ticket) => inner.granted.remove(&ticket),
+            None => inner.free.pop(),
+        };
+        if let Some(id) = id {
+            inner.slots[id] = Slot::Reserved;
+            self.ticket = None;
+            return Poll::Ready(Submission { queue, id });
+        }
+        let ticket = match self.ticket {
+            Some(ticket) => ticket,
+            None => {
+                let ticket = inner.next_ticket;
+                inner.next_ticket += 1;
+                ticket
+            }
+        };
+        inner.waiters.insert(ticket, cx.waker().clone());
+        self.ticket = Some(ticket);
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Reserve<'_, T> {
+    fn drop(&mut self) {
+        let waker = match self.ticket {
+            Some(ticket) => {
+                let mut inner = self.queue.inner.lock();
+                inner.waiters.remove(&ticket);
+                // we may have been handed a slot, pass it on
+                match inner.granted.remove(&ticket) {
+                    Some(id) => inner.release(id),
+                    None => None,
+                }
+            }
+            None => None,
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// A reserved slot, whose id is to be written into the request descriptor.
+///
+/// Dropping it without submitting frees the slot.
+pub struct Submission<'a, T> {
+    queue: &'a CompletionQueue<T>,
+    id: usize,
+}
+
+impl<'a, T> Submission<'a, T> {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Mark the request as posted to the device, and wait for its completion.
+    pub fn submit(self) -> Completion<'a, T> {
+        let (queue, id) = (self.queue, self.id);
+        core::mem::forget(self);
+        queue.inner.lock().slots[id] = Slot::InFlight(None);
+        Completion { queue, id }
+    }
+}
+
+impl<T> Drop for Submission<'_, T> {
+    fn drop(&mut self) {
+        let waker = self.queue.inner.lock().release(self.id);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Future of a request in flight, returned by [`Submission::submit`].
+///
+/// Dropping it cancels the request: the result is discarded when it arrives.
+pub struct Completion<'a, T> {
+    queue: &'a CompletionQueue<T>,
+    id: usize,
+}
+
+impl<T> Completion<'_, T> {
+    pub fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl<T> Future for Completion<'_, T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut inner = self.queue.inner.lock();
+        let inner = &mut *inner;
+        let slot = &mut inner.slots[self.id];
+        match slot {
+            Slot::InFlight(waker) => {
+                *waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Slot::Completed(_) => {
+                let value = match core::mem::replace(slot, Slot::Reserved) {
+                    Slot::Completed(value) => value,
+                    _ => unreachable!(),
+                };
+                Poll::Ready(value)
+            }
+            _ => panic!("completion queue: polled a finished request {}", self.id),
+        }
+    }
+}
+
+impl<T> Drop for Completion<'_, T> {
+    fn drop(&mut self) {
+        let (waker, dropped) = {
+            let mut inner = self.queue.inner.lock();
+            match core::mem::replace(&mut inner.slots[self.id], Slot::Cancelled) {
+                Slot::InFlight(_) => {
+                    trace!("completion queue: cancel request {}", self.id);
+                    (None, None)
+                }
+                // finished or completed but never taken
+                other => {
+                    let waker = inner.release(self.id);
+                    (waker, Some(other))
+                }
+            }
+        };
+        drop(dropped);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asynchronous::test_util::Polled;
+
+    fn waiter(queue: &CompletionQueue<u32>) -> Polled<Reserve<'_, u32>> {
+        let mut waiter = Polled::new(queue.reserve());
+        assert!(waiter.poll().is_none());
+        waiter
+    }
+
+    #[test]
+    fn waiters_get_slots_in_arrival_order() {
+        let queue = CompletionQueue::new(1);
+        let mut held = Polled::new(queue.reserve()).poll();
+        assert!(held.is_some());
+        let mut waiters = [waiter(&queue), waiter(&queue), waiter(&queue)];
+        for i in 0..waiters.len() {
+            drop(held.take());
+            for (j, waiter) in waiters.iter().enumerate().skip(i) {
+                assert_eq!(waiter.woken(), i == j);
+            }
+            // neither later waiters nor newcomers take the slot of the woken one
+            for waiter in waiters.iter_mut().skip(i + 1) {
+                assert!(waiter.poll().is_none());
+            }
+            assert!(Polled::new(queue.reserve()).poll().is_none());
+            held = waiters[i].poll();
+            assert!(held.is_some());
+            assert_eq!(queue.in_flight(), 1);
+        }
+        drop(held);
+        assert_eq!(queue.in_flight(), 0);
+    }
+
+    #[test]
+    fn dropped_waiter_passes_its_slot_on() {
+        let queue = CompletionQueue::new(1);
+        let held = Polled::new(queue.reserve()).poll();
+        let second = waiter(&queue);
+        let third = waiter(&queue);
+        let mut fourth = waiter(&queue);
+        // dropped while waiting
+        drop(second);
+        drop(held);
+        assert!(third.woken());
+        assert!(!fourth.woken());
+        // dropped after being handed the slot
+        drop(third);
+        assert!(fourth.woken());
+        let held = fourth.poll().unwrap();
+        assert_eq!(held.id(), 0);
+        drop(held);
+        assert_eq!(queue.in_flight(), 0);
+    }
+
+    #[test]
+    fn completions_out_of_order() {
+        let queue = CompletionQueue::new(2);
+        let a = Polled::new(queue.reserve()).poll().unwrap();
+        let b = Polled::new(queue.reserve()).poll().unwrap();
+        let (a_id, b_id) = (a.id(), b.id());
+        let mut a = Polled::new(a.submit());
+        let mut b = Polled::new(b.submit());
+        assert_eq!(a.poll(), None);
+        assert_eq!(b.poll(), None);
+        assert!(queue.complete(b_id, 2));
+        assert!(b.woken());
+        assert!(!a.woken());
+        assert!(queue.complete(a_id, 1));
+        assert!(!queue.complete(a_id, 1));
+        assert_eq!(b.poll(), Some(2));
+        assert_eq!(a.poll(), Some(1));
+    }
+
+    #[test]
+    fn cancelled_slot_waits_for_the_device() {
+        let queue = CompletionQueue::new(1);
+        let request = Polled::new(queue.reserve()).poll().unwrap();
+        let id = request.id();
+        drop(request.submit());
+        assert_eq!(queue.cancelled(), [id]);
+        let mut next = waiter(&queue);
+        assert!(queue.complete(id, 7));
+        assert!(next.woken());
+        assert!(next.poll().is_some());
+        assert!(queue.cancelled().is_empty());
+    }
+}
@@ -1,35 +1,421 @@
-use alloc::boxed::Box;
-use async_task::Task;
+use super::await_tree::{PollGuard, MAX_CPUS};
+use super::io::Reactor;
+use super::raw::{self, Task, TaskQueue};
+use super::stats::{Clock, CompletionGuard, Counters, ExecutorStats};
+use super::task::{TaskId, TaskInfo, TaskState};
+use super::unwind::Contained;
+use crate::instrument::{Instrument, NoInstrument};
+use crate::platform;
+use crate::scheduler::CpuMask;
+use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::fmt::{self, Write};
 use core::future::Future;
+use core::ops::Deref;
+use core::pin::Pin;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use core::task::{Context, Poll};
 use lazy_static::*;
 use log::*;
-use queueue::queue::nonblocking::*;
+use spin::{Mutex, Once};
 
-type ExecutionTag = ();
+type Registry = Mutex<BTreeMap<TaskId, Arc<TaskInfo>>>;
 
-pub struct Executor {
-    queue: StaticSpinQueue<Task<ExecutionTag>, 16>,
+lazy_static! {
+    /// The task being polled on each CPU.
+    static ref RUNNING: Vec<AtomicPtr<TaskInfo>> =
+        (0..MAX_CPUS).map(|_| AtomicPtr::new(ptr::null_mut())).collect();
+}
+
+/// Tag of every task: its info, registered in the executor while the task lives.
+pub struct ExecutionTag {
+    info: Arc<TaskInfo>,
+    registry: &'static Registry,
+}
+
+impl Deref for ExecutionTag {
+    type Target = TaskInfo;
+
+    fn deref(&self) -> &TaskInfo {
+        &self.info
+    }
+}
+
+impl Drop for ExecutionTag {
+    fn drop(&mut self) {
+        self.registry.lock().remove(&self.info.id());
+    }
+}
+
+/// What happens when a task panics.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Let the panic through, bringing the kernel down.
+    #[default]
+    Abort,
+    /// Mark the task failed, report it and go on with other tasks.
+    /// Needs `panic = "unwind"` and the `std` or `unwind` feature,
+    /// otherwise the panic aborts anyway.
+    Contain,
+}
+
+/// Why a task did not run to completion.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was cancelled before completing.
+    Cancelled,
+    /// The task panicked and was contained.
+    Panicked,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => f.write_str("task was cancelled"),
+            JoinError::Panicked => f.write_str("task panicked"),
+        }
+    }
+}
+
+/// Awaits the end of a task. Dropping the handle detaches the task.
+pub struct JoinHandle(raw::JoinHandle<Result<(), JoinError>, ExecutionTag>);
+
+impl JoinHandle {
+    pub fn info(&self) -> &TaskInfo {
+        self.0.meta()
+    }
+
+    /// Cancel the task. The handle resolves to `Err(JoinError::Cancelled)`.
+    pub fn cancel(&self) {
+        self.0.cancel()
+    }
+}
+
+impl Future for JoinHandle {
+    type Output = Result<(), JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.0)
+            .poll(cx)
+            .map(|ret| ret.unwrap_or(Err(JoinError::Cancelled)))
+    }
+}
+
+/// Called when a contained task panics.
+pub type FailureHandler = fn(task: &TaskInfo);
+
+/// Called when a task is polled or completes after its deadline,
+/// with the task, its deadline and the current time.
+pub type DeadlineMissHandler = fn(task: &TaskInfo, deadline: u64, now: u64);
+
+/// Whether task `a` is polled before task `b`:
+/// earliest deadline first, then best-effort tasks by priority.
+fn runs_before(a: &ExecutionTag, b: &ExecutionTag) -> bool {
+    match (a.deadline(), b.deadline()) {
+        (Some(a), Some(b)) => a < b,
+        (Some(_), None) => true,
+        (None, Some(_)) => false,
+        (None, None) => a.priority() > b.priority(),
+    }
+}
+
+/// Task factory, which can be used in order to configure the properties of a new task.
+#[derive(Debug, Default)]
+pub struct Builder {
+    name: Option<String>,
+    priority: u8,
+    affinity: CpuMask,
+    deadline: Option<u64>,
+    failure: FailurePolicy,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Builder::default()
+    }
+
+    /// Names the task, which shows up in [`dump`].
+    pub fn name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Sets the priority among best-effort tasks. Higher runs first, the default is 0.
+    pub fn priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Restricts the task to the CPUs in `affinity`.
+    pub fn affinity(mut self, affinity: CpuMask) -> Self {
+        self.affinity = affinity;
+        self
+    }
+
+    /// Gives the task an absolute deadline on the executor clock.
+    ///
+    /// Ready tasks with a deadline are polled earliest deadline first,
+    /// and before any best-effort task. A periodic task gives its later jobs
+    /// deadlines of their own with [`set_deadline`].
+    pub fn deadline(mut self, deadline: u64) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sets what happens when the task panics, aborting by default.
+    pub fn failure_policy(mut self, policy: FailurePolicy) -> Self {
+        self.failure = policy;
+        self
+    }
+
+    /// Spawns the task on the global executor.
+    pub fn spawn<F>(self, fut: F) -> JoinHandle
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn_on(&GLOBAL_EXECUTOR, fut)
+    }
+
+    /// Spawns the task on `executor`.
+    pub fn spawn_on<F, I>(self, executor: &'static Executor<I>, fut: F) -> JoinHandle
+    where
+        F: Future<Output = ()> + Send + 'static,
+        I: Instrument,
+    {
+        executor.spawn_with(self, fut)
+    }
+}
+
+pub struct Executor<I: Instrument = NoInstrument> {
+    queue: TaskQueue<ExecutionTag>,
+    counters: Counters,
+    clock: Once<&'static dyn Clock>,
+    deadline_miss: Once<DeadlineMissHandler>,
+    failure: Once<FailureHandler>,
+    reactor: Once<&'static dyn Reactor>,
+    tasks: Registry,
+    instrument: I,
 }
 
 impl Executor {
     pub fn new() -> Self {
+        Self::with_instrument(NoInstrument)
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: Instrument> Executor<I> {
+    /// Create an executor reporting its events to `instrument`.
+    pub fn with_instrument(instrument: I) -> Self {
         Self {
-            queue: StaticSpinQueue::default(),
+            queue: TaskQueue::new(),
+            counters: Counters::default(),
+            clock: Once::new(),
+            deadline_miss: Once::new(),
+            failure: Once::new(),
+            reactor: Once::new(),
+            tasks: Mutex::new(BTreeMap::new()),
+            instrument,
         }
     }
 
-    pub fn spawn<F>(&'static self, fut: F) -> async_task::JoinHandle<(), ExecutionTag>
+    /// Set the clock used to measure wake-to-poll latency.
+    ///
+    /// Only the first call takes effect. Without a clock all latencies are zero.
+    pub fn set_clock(&self, clock: &'static dyn Clock) {
+        self.clock.call_once(|| clock);
+    }
+
+    /// Set the reactor polled before the executor goes idle.
+    /// Only the first call takes effect.
+    pub fn set_reactor(&self, reactor: &'static dyn Reactor) {
+        self.reactor.call_once(|| reactor);
+    }
+
+    /// Poll the reactor. Return the number of tasks woken.
+    pub fn poll_reactor(&self) -> usize {
+        self.reactor.r#try().map_or(0, |reactor| reactor.poll())
+    }
+
+    /// Set the handler of deadline misses. Only the first call takes effect.
+    pub fn set_deadline_miss_handler(&self, handler: DeadlineMissHandler) {
+        self.deadline_miss.call_once(|| handler);
+    }
+
+    /// Set the handler of contained task panics. Only the first call takes effect.
+    pub fn set_failure_handler(&self, handler: FailureHandler) {
+        self.failure.call_once(|| handler);
+    }
+
+    fn report_failure(&self, info: &TaskInfo) {
+        error!(
+            "task {} ({}) panicked",
+            info.id(),
+            info.name().unwrap_or("<unnamed>")
+        );
+        if let Some(handler) = self.failure.r#try() {
+            handler(info);
+        }
+    }
+
+    fn check_deadline(&self, info: &TaskInfo) {
+        let now = self.now();
+        if info.check_deadline(now) {
+            let deadline = info.deadline().unwrap();
+            warn!(
+                "task {} missed its deadline {} at {}",
+                info.id(),
+                deadline,
+                now
+            );
+            if let Some(handler) = self.deadline_miss.r#try() {
+                handler(info, deadline, now);
+            }
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.clock.r#try().map_or(0, |clock| clock.now())
+    }
+
+    pub fn spawn<F>(&'static self, fut: F) -> JoinHandle
     where
         F: Future<Output = ()> + Send + 'static,
     {
-        let prod = self.queue.producer();
-        let schedule = move |task| {
+        self.spawn_with(Builder::new(), fut)
+    }
+
+    fn spawn_with<F>(&'static self, builder: Builder, fut: F) -> JoinHandle
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let info = Arc::new(TaskInfo::new(
+            builder.name,
+            builder.priority,
+            builder.affinity,
+            builder.deadline,
+        ));
+        self.tasks.lock().insert(info.id(), info.clone());
+        self.instrument.on_spawn(info.id().as_usize());
+        let guard = CompletionGuard::new(&self.counters);
+        let fut = {
+            let info = info.clone();
+            let fut = Contained::new(fut, builder.failure);
+            async move {
+                let ret = fut.await;
+                match ret {
+                    Ok(()) => {
+                        info.set_state(TaskState::Completed);
+                        guard.complete();
+                    }
+                    Err(_) => {
+                        info.set_state(TaskState::Failed);
+                        guard.fail();
+                    }
+                }
+                ret
+            }
+        };
+        let schedule = move |task: Task<ExecutionTag>| {
             trace!("Pushed");
-            prod.push(task).unwrap();
+            let id = task.meta().id().as_usize();
+            let affinity = task.meta().affinity();
+            self.instrument.on_wake(id);
+            task.meta().woken(self.now());
+            self.counters.pushed();
+            self.queue.push_by(task, runs_before);
+            self.instrument.on_push(id);
+            platform::wake_idle_cpu_in(affinity.0);
+        };
+        let tag = ExecutionTag {
+            info,
+            registry: &self.tasks,
         };
-        let (task, handle) = async_task::spawn(fut, schedule, ());
+        let (task, handle) = raw::spawn(fut, schedule, tag);
         task.schedule();
-        handle
+        JoinHandle(handle)
+    }
+
+    /// Take a snapshot of the executor counters.
+    pub fn stats(&self) -> ExecutorStats {
+        self.counters.snapshot()
+    }
+
+    /// Print every live task, its state, poll count, last wakeup
+    /// and the await tree it is parked on.
+    pub fn dump(&self, w: &mut dyn Write) -> fmt::Result {
+        let now = self.now();
+        writeln!(w, "async tasks at {}:", now)?;
+        writeln!(
+            w,
+            "{:>6} {:<10} {:>8} {:>12}  name",
+            "id", "state", "polls", "woken"
+        )?;
+        for info in self.tasks.lock().values() {
+            writeln!(
+                w,
+                "{:>6} {:<10} {:>8} {:>12}  {}",
+                info.id(),
+                info.state(),
+                info.polls(),
+                info.woken_at(),
+                info.name().unwrap_or("<unnamed>")
+            )?;
+            info.await_tree().dump(w, 8)?;
+        }
+        Ok(())
+    }
+
+    /// Whether any ready task is allowed on this CPU.
+    fn has_ready(&self) -> bool {
+        let cpu = platform::cpu_id();
+        self.queue.any_where(|tag| tag.affinity().contains(cpu))
+    }
+
+    /// Run one ready task allowed on this CPU, earliest deadline first.
+    /// Return false if there is none.
+    pub fn run_once(&self) -> bool {
+        let cpu = platform::cpu_id();
+        let task = match self.queue.pop_where(|tag| tag.affinity().contains(cpu)) {
+            Some(task) => task,
+            None => return false,
+        };
+        trace!("Popped");
+        let info = task.meta().info.clone();
+        let id = info.id().as_usize();
+        self.instrument.on_pop(id);
+        let latency = self.now().wrapping_sub(info.woken_at());
+        info.poll_start();
+        self.counters.popped(latency);
+        self.instrument.on_poll_start(id);
+        self.check_deadline(&info);
+        {
+            let _tree = PollGuard::enter(info.await_tree());
+            let running = RUNNING.get(cpu);
+            if let Some(running) = running {
+                running.store(&*info as *const _ as *mut _, Ordering::Release);
+            }
+            task.run();
+            if let Some(running) = running {
+                running.store(ptr::null_mut(), Ordering::Release);
+            }
+        }
+        info.poll_end();
+        let state = info.state();
+        match state {
+            TaskState::Completed => self.check_deadline(&info),
+            TaskState::Failed => self.report_failure(&info),
+            _ => {}
+        }
+        let done = state == TaskState::Completed || state == TaskState::Failed;
+        self.instrument.on_poll_end(id, done);
+        trace!("Run over");
+        true
     }
 }
 
@@ -47,15 +433,156 @@ where
     GLOBAL_EXECUTOR.spawn(fut);
 }
 
+/// Give the next job of the running task the absolute deadline `deadline`.
+///
+/// The task is ordered by it from its next wakeup on, and missing it is reported
+/// even if an earlier job missed its own. Does nothing outside of a task.
+pub fn set_deadline(deadline: u64) {
+    let running = RUNNING.get(platform::cpu_id());
+    let ptr = running.map_or(ptr::null_mut(), |running| running.load(Ordering::Acquire));
+    // the info outlives the poll, and we only get here from inside the poll
+    if let Some(info) = unsafe { ptr.as_ref() } {
+        info.set_deadline(deadline);
+    }
+}
+
+/// Set the clock of the global executor.
+pub fn set_clock(clock: &'static dyn Clock) {
+    GLOBAL_EXECUTOR.set_clock(clock);
+}
+
+/// Take a snapshot of the global executor counters.
+pub fn stats() -> ExecutorStats {
+    GLOBAL_EXECUTOR.stats()
+}
+
+/// Set the reactor of the global executor.
+pub fn set_reactor(reactor: &'static dyn Reactor) {
+    GLOBAL_EXECUTOR.set_reactor(reactor);
+}
+
+/// Set the deadline miss handler of the global executor.
+pub fn set_deadline_miss_handler(handler: DeadlineMissHandler) {
+    GLOBAL_EXECUTOR.set_deadline_miss_handler(handler);
+}
+
+/// Set the failure handler of the global executor.
+pub fn set_failure_handler(handler: FailureHandler) {
+    GLOBAL_EXECUTOR.set_failure_handler(handler);
+}
+
+/// Print every task of the global executor.
+pub fn dump(w: &mut dyn Write) -> fmt::Result {
+    GLOBAL_EXECUTOR.dump(w)
+}
+
 pub fn run() -> ! {
+    let idle_cpus = platform::idle_cpus();
     loop {
-        if let Some(task) = GLOBAL_EXECUTOR.queue.pop() {
-            trace!("Popped");
-            task.run();
-            trace!("Run over");
-        } else {
-            x86_64::instructions::interrupts::enable_interrupts_and_hlt();
-            x86_64::instructions::interrupts::disable();
+        if GLOBAL_EXECUTOR.run_once() || GLOBAL_EXECUTOR.poll_reactor() != 0 {
+            continue;
+        }
+        // mark idle before the last check, so a task pushed from now on sends us an IPI
+        let cpu = platform::cpu_id();
+        idle_cpus.enter(cpu);
+        if GLOBAL_EXECUTOR.has_ready() {
+            idle_cpus.leave(cpu);
+            continue;
         }
+        x86_64::instructions::interrupts::enable_interrupts_and_hlt();
+        x86_64::instructions::interrupts::disable();
+        idle_cpus.leave(cpu);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicU64;
+
+    /// Held by tests polling tasks, which all run as CPU 0.
+    pub(crate) static CPU0: Mutex<()> = Mutex::new(());
+
+    struct TestClock(AtomicU64);
+
+    impl Clock for TestClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Pending once, so the task goes back to the ready queue.
+    struct Yield(bool);
+
+    impl Future for Yield {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn earliest_deadline_first_then_priority() {
+        let _cpu = CPU0.lock();
+        let ex: &'static Executor = Box::leak(Box::new(Executor::new()));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let spec = [
+            (0, None),
+            (5, None),
+            (0, Some(30)),
+            (1, None),
+            (0, Some(10)),
+        ];
+        for (i, &(priority, deadline)) in spec.iter().enumerate() {
+            let mut builder = Builder::new().priority(priority);
+            if let Some(deadline) = deadline {
+                builder = builder.deadline(deadline);
+            }
+            let log = log.clone();
+            builder.spawn_on(ex, async move { log.lock().push(i) });
+        }
+        while ex.run_once() {}
+        assert_eq!(*log.lock(), [4, 2, 1, 3, 0]);
+    }
+
+    static MISSES: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+    fn record_miss(_task: &TaskInfo, deadline: u64, now: u64) {
+        MISSES.lock().push((deadline, now));
+    }
+
+    #[test]
+    fn every_job_reports_its_miss() {
+        let _cpu = CPU0.lock();
+        let ex: &'static Executor = Box::leak(Box::new(Executor::new()));
+        let clock: &'static TestClock = Box::leak(Box::new(TestClock(AtomicU64::new(10))));
+        ex.set_clock(clock);
+        ex.set_deadline_miss_handler(record_miss);
+        Builder::new().deadline(5).spawn_on(ex, async {
+            // job 2
+            set_deadline(20);
+            Yield(false).await;
+            // still job 2, whose miss was already reported
+            Yield(false).await;
+            // job 3, completed in time
+            set_deadline(40);
+            Yield(false).await;
+        });
+        assert!(ex.run_once());
+        assert_eq!(*MISSES.lock(), [(5, 10)]);
+        clock.0.store(30, Ordering::Relaxed);
+        assert!(ex.run_once());
+        assert!(ex.run_once());
+        assert_eq!(*MISSES.lock(), [(5, 10), (20, 30)]);
+        clock.0.store(35, Ordering::Relaxed);
+        while ex.run_once() {}
+        assert_eq!(*MISSES.lock(), [(5, 10), (20, 30)]);
+        assert_eq!(ex.stats().completed, 1);
     }
 }
@@ -0,0 +1,279 @@
+//! Async I/O for device drivers
+//!
+//! A driver implements [`Reactor`] for its devices: tasks register interest in a source,
+//! and the executor polls the reactor before halting, which wakes the tasks whose
+//! source became ready. Byte streams are exposed through [`AsyncRead`] and [`AsyncWrite`].
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::ops::BitOr;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+/// An I/O source of a reactor, such as an IRQ line or a queue index.
+pub type SourceId = usize;
+
+/// The readiness a task waits for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Interest(u8);
+
+impl Interest {
+    pub const READABLE: Interest = Interest(1);
+    pub const WRITABLE: Interest = Interest(2);
+
+    pub fn is_readable(self) -> bool {
+        self.0 & Self::READABLE.0 != 0
+    }
+
+    pub fn is_writable(self) -> bool {
+        self.0 & Self::WRITABLE.0 != 0
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, rhs: Interest) -> Interest {
+        Interest(self.0 | rhs.0)
+    }
+}
+
+/// Readiness notifications of a group of devices.
+pub trait Reactor: Send + Sync {
+    /// Wake `waker` once `source` is ready for `interest`.
+    ///
+    /// A later registration for the same source and direction replaces the earlier one.
+    fn register(&self, source: SourceId, interest: Interest, waker: &Waker);
+    /// Drop every registration of `source`.
+    fn deregister(&self, source: SourceId);
+    /// Check the devices and wake the tasks of every ready source.
+    ///
+    /// Return the number of tasks woken.
+    fn poll(&self) -> usize;
+}
+
+/// Table of registered wakers, for reactors to build on.
+#[derive(Default)]
+pub struct Registrations {
+    readers: Mutex<BTreeMap<SourceId, Waker>>,
+    writers: Mutex<BTreeMap<SourceId, Waker>>,
+}
+
+impl Registrations {
+    pub fn new() -> Self {
+        Registrations::default()
+    }
+
+    pub fn register(&self, source: SourceId, interest: Interest, waker: &Waker) {
+        if interest.is_readable() {
+            self.readers.lock().insert(source, waker.clone());
+        }
+        if interest.is_writable() {
+            self.writers.lock().insert(source, waker.clone());
+        }
+    }
+
+    pub fn deregister(&self, source: SourceId) {
+        self.readers.lock().remove(&source);
+        self.writers.lock().remove(&source);
+    }
+
+    /// Wake the tasks waiting for `source` to be `ready`. Return the number woken.
+    pub fn wake(&self, source: SourceId, ready: Interest) -> usize {
+        let mut wakers = Vec::new();
+        if ready.is_readable() {
+            wakers.extend(self.readers.lock().remove(&source));
+        }
+        if ready.is_writable() {
+            wakers.extend(self.writers.lock().remove(&source));
+        }
+        let n = wakers.len();
+        for waker in wakers {
+            waker.wake();
+        }
+        n
+    }
+
+    /// Whether any task is waiting on any source.
+    pub fn is_empty(&self) -> bool {
+        self.readers.lock().is_empty() && self.writers.lock().is_empty()
+    }
+}
+
+/// Read bytes asynchronously.
+pub trait AsyncRead {
+    type Error;
+
+    /// Attempt to read into `buf`, returning the number of bytes read.
+    ///
+    /// On `Pending` the task is woken once the source may be readable again.
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Self::Error>>;
+}
+
+/// Write bytes asynchronously.
+pub trait AsyncWrite {
+    type Error;
+
+    /// Attempt to write from `buf`, returning the number of bytes written.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>>;
+
+    /// Attempt to flush buffered data to the device.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>>;
+}
+
+/// `async fn`-style helpers on top of [`AsyncRead`].
+pub trait AsyncReadExt: AsyncRead + Unpin {
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadFuture<'a, Self> {
+        ReadFuture { io: self, buf }
+    }
+}
+
+impl<T: AsyncRead + Unpin + ?Sized> AsyncReadExt for T {}
+
+/// `async fn`-style helpers on top of [`AsyncWrite`].
+pub trait AsyncWriteExt: AsyncWrite + Unpin {
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> WriteFuture<'a, Self> {
+        WriteFuture { io: self, buf }
+    }
+
+    fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> WriteAllFuture<'a, Self> {
+        WriteAllFuture { io: self, buf }
+    }
+
+    fn flush(&mut self) -> FlushFuture<'_, Self> {
+        FlushFuture { io: self }
+    }
+}
+
+impl<T: AsyncWrite + Unpin + ?Sized> AsyncWriteExt for T {}
+
+pub struct ReadFuture<'a, T: ?Sized> {
+    io: &'a mut T,
+    buf: &'a mut [u8],
+}
+
+impl<T: AsyncRead + Unpin + ?Sized> Future for ReadFuture<'_, T> {
+    type Output = Result<usize, T::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        Pin::new(&mut *this.io).poll_read(cx, this.buf)
+    }
+}
+
+pub struct WriteFuture<'a, T: ?Sized> {
+    io: &'a mut T,
+    buf: &'a [u8],
+}
+
+impl<T: AsyncWrite + Unpin + ?Sized> Future for WriteFuture<'_, T> {
+    type Output = Result<usize, T::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        Pin::new(&mut *this.io).poll_write(cx, this.buf)
+    }
+}
+
+pub struct WriteAllFuture<'a, T: ?Sized> {
+    io: &'a mut T,
+    buf: &'a [u8],
+}
+
+impl<T: AsyncWrite + Unpin + ?Sized> Future for WriteAllFuture<'_, T> {
+    type Output = Result<(), T::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        while !this.buf.is_empty() {
+            match Pin::new(&mut *this.io).poll_write(cx, this.buf) {
+                Poll::Ready(Ok(n)) => this.buf = &this.buf[n..],
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+pub struct FlushFuture<'a, T: ?Sized> {
+    io: &'a mut T,
+}
+
+impl<T: AsyncWrite + Unpin + ?Sized> Future for FlushFuture<'_, T> {
+    type Output = Result<(), T::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut *self.get_mut().io).poll_flush(cx)
+    }
+}
+
+/// Split a device into halves that can be moved into different tasks.
+pub fn split<T>(io: T) -> (ReadHalf<T>, WriteHalf<T>)
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let io = Arc::new(Mutex::new(io));
+    (ReadHalf { io: io.clone() }, WriteHalf { io })
+}
+
+/// The readable half of a device, created by [`split`].
+pub struct ReadHalf<T> {
+    io: Arc<Mutex<T>>,
+}
+
+/// The writable half of a device, created by [`split`].
+pub struct WriteHalf<T> {
+    io: Arc<Mutex<T>>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ReadHalf<T> {
+    type Error = T::Error;
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        Pin::new(&mut *self.io.lock()).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for WriteHalf<T> {
+    type Error = T::Error;
+
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Self::Error>> {
+        Pin::new(&mut *self.io.lock()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut *self.io.lock()).poll_flush(cx)
+    }
+}
+
+impl<T> ReadHalf<T> {
+    /// Put the halves back together. Return them unchanged if they don't match.
+    pub fn unsplit(self, write: WriteHalf<T>) -> Result<T, (ReadHalf<T>, WriteHalf<T>)> {
+        if !Arc::ptr_eq(&self.io, &write.io) {
+            return Err((self, write));
+        }
+        drop(write);
+        match Arc::try_unwrap(self.io) {
+            Ok(io) => Ok(io.into_inner()),
+            Err(_) => unreachable!(),
+        }
+    }
+}
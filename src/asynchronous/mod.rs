@@ -1 +1,16 @@
+pub mod await_tree;
+pub mod blocking;
+pub mod completion;
 pub mod executor;
+pub mod io;
+pub mod periodic;
+pub mod raw;
+#[cfg(any(test, feature = "userland"))]
+pub mod sim;
+pub mod stats;
+pub mod stream;
+pub mod task;
+#[cfg(test)]
+mod test_util;
+pub mod timer;
+mod unwind;
@@ -0,0 +1,90 @@
+//! Intervals and periodic tasks
+
+use super::executor::{self, JoinHandle};
+use super::stream::{Stream, StreamExt};
+use super::timer::{self, Sleep, TimerQueue};
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// What an interval does with the ticks missed while its consumer was busy.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MissedTicks {
+    /// Fire one late tick, then drop the missed ones and go on with the original schedule.
+    Skip,
+    /// Fire every missed tick back to back until caught up.
+    CatchUp,
+}
+
+/// A stream of the scheduled times of ticks `period` apart.
+pub struct Interval {
+    timers: Arc<TimerQueue>,
+    period: u64,
+    next: u64,
+    policy: MissedTicks,
+    sleep: Option<Sleep>,
+}
+
+/// Tick every `period`, starting one period from now.
+pub fn interval(timers: Arc<TimerQueue>, period: u64, policy: MissedTicks) -> Interval {
+    assert!(period > 0, "interval: period must be positive");
+    let next = timers.now() + period;
+    Interval {
+        timers,
+        period,
+        next,
+        policy,
+        sleep: None,
+    }
+}
+
+impl Interval {
+    pub fn period(&self) -> u64 {
+        self.period
+    }
+}
+
+impl Stream for Interval {
+    type Item = u64;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        let this = self.get_mut();
+        let now = this.timers.now();
+        if now < this.next {
+            let timers = &this.timers;
+            let next = this.next;
+            let sleep = this
+                .sleep
+                .get_or_insert_with(|| Sleep::until(timers.clone(), next));
+            if Pin::new(sleep).poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        this.sleep = None;
+        let tick = this.next;
+        this.next = match this.policy {
+            MissedTicks::CatchUp => tick + this.period,
+            MissedTicks::Skip => {
+                let missed = (this.timers.now() - tick) / this.period;
+                tick + (missed + 1) * this.period
+            }
+        };
+        Poll::Ready(Some(tick))
+    }
+}
+
+/// Spawn a task on the global executor running `f` every `period` ticks
+/// of the kernel timer queue. `f` gets the scheduled time of the tick.
+pub fn spawn_periodic<F, Fut>(period: u64, policy: MissedTicks, mut f: F) -> JoinHandle
+where
+    F: FnMut(u64) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut ticks = interval(timer::global(), period, policy);
+    executor::Builder::new().spawn(async move {
+        while let Some(tick) = ticks.next().await {
+            f(tick).await;
+        }
+    })
+}
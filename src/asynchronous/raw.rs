@@ -0,0 +1,851 @@
+//! Task representation
+//!
+//! A task is a single allocation: a [`Header`] followed by the schedule function and
+//! the future or its output. The header holds the state bits, a vtable, the link of the
+//! intrusive [`TaskQueue`] and the task metadata, so waking a task and putting it in a
+//! ready queue never allocates.
+//!
+//! Three kinds of handles point to a task, each holding a reference:
+//! the runnable [`Task`], the [`JoinHandle`] and every `Waker`.
+//! At most one `Task` exists at a time, and only its holder touches the future.
+
+use alloc::boxed::Box;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::mem::{self, ManuallyDrop, MaybeUninit};
+use core::pin::Pin;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use spin::Mutex;
+
+/// A `Task` exists, either in a ready queue or about to run.
+const SCHEDULED: usize = 1 << 0;
+/// The future is being polled.
+const RUNNING: usize = 1 << 1;
+/// The future is done and its output is stored.
+const COMPLETED: usize = 1 << 2;
+/// Cancelled, or the output is taken. The future is never polled again.
+const CLOSED: usize = 1 << 3;
+
+pub struct Header<M> {
+    state: AtomicUsize,
+    refs: AtomicUsize,
+    vtable: &'static TaskVTable,
+    /// Link of the intrusive ready queue, only touched under the queue lock.
+    next: AtomicPtr<Header<M>>,
+    /// Waker of the task awaiting the `JoinHandle`.
+    awaiter: Mutex<Option<Waker>>,
+    meta: M,
+}
+
+struct TaskVTable {
+    schedule: unsafe fn(*const ()),
+    run: unsafe fn(*const ()),
+    /// Drop the future or the output, whichever is stored.
+    drop_stage: unsafe fn(*const ()),
+    take_output: unsafe fn(*const (), *mut ()),
+    dealloc: unsafe fn(*const ()),
+}
+
+impl<M: Send + Sync + 'static> Header<M> {
+    const WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+        Self::clone_waker,
+        Self::wake,
+        Self::wake_by_ref,
+        Self::drop_waker,
+    );
+
+    fn inc_ref(&self) {
+        self.refs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe fn dec_ref(ptr: *const Self) {
+        if (*ptr).refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            ((*ptr).vtable.dealloc)(ptr as *const ());
+        }
+    }
+
+    unsafe fn notify_awaiter(&self) {
+        let waker = self.awaiter.lock().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Mark the task closed, and schedule it if idle, so that its future gets dropped.
+    unsafe fn cancel(ptr: *const Self) {
+        let header = &*ptr;
+        let mut state = header.state.load(Ordering::Acquire);
+        loop {
+            if state & CLOSED != 0 {
+                return;
+            }
+            if state & COMPLETED != 0 {
+                // nobody will take the output, drop it unless a racing cancel did
+                if header.state.fetch_or(CLOSED, Ordering::AcqRel) & CLOSED == 0 {
+                    (header.vtable.drop_stage)(ptr as *const ());
+                }
+                return;
+            }
+            let idle = state & (SCHEDULED | RUNNING) == 0;
+            let new = if idle {
+                state | SCHEDULED | CLOSED
+            } else {
+                state | CLOSED
+            };
+            match header.state.compare_exchange_weak(
+                state,
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    if idle {
+                        header.inc_ref();
+                        (header.vtable.schedule)(ptr as *const ());
+                    }
+                    return;
+                }
+                Err(s) => state = s,
+            }
+        }
+    }
+
+    unsafe fn clone_waker(ptr: *const ()) -> RawWaker {
+        (*(ptr as *const Self)).inc_ref();
+        RawWaker::new(ptr, &Self::WAKER_VTABLE)
+    }
+
+    unsafe fn wake(ptr: *const ()) {
+        Self::wake_by_ref(ptr);
+        Self::dec_ref(ptr as *const Self);
+    }
+
+    unsafe fn wake_by_ref(ptr: *const ()) {
+        let header = &*(ptr as *const Self);
+        let mut state = header.state.load(Ordering::Acquire);
+        loop {
+            if state & (SCHEDULED | COMPLETED | CLOSED) != 0 {
+                return;
+            }
+            match header.state.compare_exchange_weak(
+                state,
+                state | SCHEDULED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    // a running task is rescheduled by `run` once the poll returns
+                    if state & RUNNING == 0 {
+                        header.inc_ref();
+                        (header.vtable.schedule)(ptr);
+                    }
+                    return;
+                }
+                Err(s) => state = s,
+            }
+        }
+    }
+
+    unsafe fn drop_waker(ptr: *const ()) {
+        Self::dec_ref(ptr as *const Self);
+    }
+}
+
+enum Stage<F: Future> {
+    Pending(F),
+    Ready(F::Output),
+    Empty,
+}
+
+#[repr(C)]
+struct RawTask<F: Future, S, M> {
+    /// Must come first, tasks are referred to by header pointers.
+    header: Header<M>,
+    schedule: S,
+    stage: UnsafeCell<Stage<F>>,
+}
+
+impl<F, S, M> RawTask<F, S, M>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+    S: Fn(Task<M>) + Send + Sync + 'static,
+    M: Send + Sync + 'static,
+{
+    const TASK_VTABLE: TaskVTable = TaskVTable {
+        schedule: Self::schedule,
+        run: Self::run,
+        drop_stage: Self::drop_stage,
+        take_output: Self::take_output,
+        dealloc: Self::dealloc,
+    };
+
+    fn allocate(future: F, schedule: S, meta: M) -> NonNull<Header<M>> {
+        let raw = Box::new(RawTask {
+            header: Header {
+                state: AtomicUsize::new(SCHEDULED),
+                // the `Task` and the `JoinHandle`
+                refs: AtomicUsize::new(2),
+                vtable: &Self::TASK_VTABLE,
+                next: AtomicPtr::new(ptr::null_mut()),
+                awaiter: Mutex::new(None),
+                meta,
+            },
+            schedule,
+            stage: UnsafeCell::new(Stage::Pending(future)),
+        });
+        unsafe { NonNull::new_unchecked(Box::into_raw(raw) as *mut Header<M>) }
+    }
+
+    /// Hand a `Task`, owning one reference, to the schedule function.
+    unsafe fn schedule(ptr: *const ()) {
+        let raw = &*(ptr as *const Self);
+        let task = Task {
+            ptr: NonNull::new_unchecked(ptr as *mut Header<M>),
+        };
+        (raw.schedule)(task);
+    }
+
+    unsafe fn drop_stage(ptr: *const ()) {
+        let raw = &*(ptr as *const Self);
+        *raw.stage.get() = Stage::Empty;
+    }
+
+    unsafe fn take_output(ptr: *const (), out: *mut ()) {
+        let raw = &*(ptr as *const Self);
+        match mem::replace(&mut *raw.stage.get(), Stage::Empty) {
+            Stage::Ready(output) => ptr::write(out as *mut F::Output, output),
+            _ => unreachable!("task output taken twice"),
+        }
+    }
+
+    unsafe fn dealloc(ptr: *const ()) {
+        drop(Box::from_raw(ptr as *mut Self));
+    }
+
+    /// Poll the future, consuming the reference of the `Task`.
+    unsafe fn run(ptr: *const ()) {
+        let raw = &*(ptr as *const Self);
+        let header = &raw.header;
+        let hptr = ptr as *const Header<M>;
+
+        let mut state = header.state.load(Ordering::Acquire);
+        loop {
+            if state & CLOSED != 0 {
+                // cancelled before it got to run
+                Self::drop_stage(ptr);
+                header.state.fetch_and(!SCHEDULED, Ordering::AcqRel);
+                header.notify_awaiter();
+                Header::dec_ref(hptr);
+                return;
+            }
+            let new = (state & !SCHEDULED) | RUNNING;
+            match header.state.compare_exchange_weak(
+                state,
+                new,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(s) => state = s,
+            }
+        }
+
+        // borrows the reference of the `Task`
+        let raw_waker = RawWaker::new(ptr, &Header::<M>::WAKER_VTABLE);
+        let waker = ManuallyDrop::new(Waker::from_raw(raw_waker));
+        let mut cx = Context::from_waker(&waker);
+        let poll = match &mut *raw.stage.get() {
+            Stage::Pending(future) => Pin::new_unchecked(future).poll(&mut cx),
+            _ => unreachable!("polled a finished task"),
+        };
+
+        match poll {
+            Poll::Ready(output) => {
+                *raw.stage.get() = Stage::Ready(output);
+                let prev = header
+                    .state
+                    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |s| {
+                        Some((s & !(RUNNING | SCHEDULED)) | COMPLETED)
+                    });
+                if prev.unwrap() & CLOSED != 0 {
+                    // cancelled while running, nobody will take the output
+                    Self::drop_stage(ptr);
+                }
+                header.notify_awaiter();
+                Header::dec_ref(hptr);
+            }
+            Poll::Pending => {
+                let mut state = header.state.load(Ordering::Acquire);
+                loop {
+                    if state & CLOSED != 0 {
+                        Self::drop_stage(ptr);
+                        header
+                            .state
+                            .fetch_and(!(RUNNING | SCHEDULED), Ordering::AcqRel);
+                        header.notify_awaiter();
+                        Header::dec_ref(hptr);
+                        return;
+                    }
+                    match header.state.compare_exchange_weak(
+                        state,
+                        state & !RUNNING,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    ) {
+                        Ok(_) => break,
+                        Err(s) => state = s,
+                    }
+                }
+                if state & SCHEDULED != 0 {
+                    // woken while running, pass our reference on
+                    Self::schedule(ptr);
+                } else {
+                    Header::dec_ref(hptr);
+                }
+            }
+        }
+    }
+}
+
+/// Create a task running `future`, passed to `schedule` whenever it is woken.
+///
+/// The returned `Task` is not scheduled yet: call [`Task::schedule`] to start it.
+pub fn spawn<F, S, M>(future: F, schedule: S, meta: M) -> (Task<M>, JoinHandle<F::Output, M>)
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+    S: Fn(Task<M>) + Send + Sync + 'static,
+    M: Send + Sync + 'static,
+{
+    let ptr = RawTask::<F, S, M>::allocate(future, schedule, meta);
+    let task = Task { ptr };
+    let handle = JoinHandle {
+        ptr,
+        _marker: PhantomData,
+    };
+    (task, handle)
+}
+
+/// A runnable task, owned by a ready queue.
+pub struct Task<M> {
+    ptr: NonNull<Header<M>>,
+}
+
+unsafe impl<M: Send + Sync> Send for Task<M> {}
+unsafe impl<M: Send + Sync> Sync for Task<M> {}
+
+impl<M> Task<M> {
+    fn header(&self) -> &Header<M> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// The metadata given at spawn.
+    pub fn meta(&self) -> &M {
+        &self.header().meta
+    }
+
+    /// Pass the task to its schedule function.
+    pub fn schedule(self) {
+        let ptr = self.ptr.as_ptr() as *const ();
+        let vtable = self.header().vtable;
+        mem::forget(self);
+        unsafe { (vtable.schedule)(ptr) }
+    }
+
+    /// Poll the future once.
+    ///
+    /// If it is woken during the poll, it is scheduled again once the poll returns.
+    pub fn run(self) {
+        let ptr = self.ptr.as_ptr() as *const ();
+        let vtable = self.header().vtable;
+        mem::forget(self);
+        unsafe { (vtable.run)(ptr) }
+    }
+
+    fn into_raw(self) -> *mut Header<M> {
+        let ptr = self.ptr.as_ptr();
+        mem::forget(self);
+        ptr
+    }
+
+    unsafe fn from_raw(ptr: *mut Header<M>) -> Self {
+        Task {
+            ptr: NonNull::new_unchecked(ptr),
+        }
+    }
+}
+
+impl<M> Drop for Task<M> {
+    /// A task dropped without running is cancelled.
+    fn drop(&mut self) {
+        let header = self.header();
+        let ptr = self.ptr.as_ptr() as *const ();
+        header.state.fetch_or(CLOSED, Ordering::AcqRel);
+        unsafe {
+            (header.vtable.drop_stage)(ptr);
+            header.state.fetch_and(!SCHEDULED, Ordering::AcqRel);
+            let waker = header.awaiter.lock().take();
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+            if header.refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+                (header.vtable.dealloc)(ptr);
+            }
+        }
+    }
+}
+
+/// Awaits the output of a task, `None` if it was cancelled.
+///
+/// Dropping the handle detaches the task, it keeps running.
+pub struct JoinHandle<R, M> {
+    ptr: NonNull<Header<M>>,
+    _marker: PhantomData<R>,
+}
+
+unsafe impl<R: Send, M: Send + Sync> Send for JoinHandle<R, M> {}
+unsafe impl<R: Send, M: Send + Sync> Sync for JoinHandle<R, M> {}
+
+impl<R, M: Send + Sync + 'static> JoinHandle<R, M> {
+    fn header(&self) -> &Header<M> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// The metadata given at spawn.
+    pub fn meta(&self) -> &M {
+        &self.header().meta
+    }
+
+    /// Cancel the task. Its future is dropped by the executor, and the handle resolves to `None`.
+    pub fn cancel(&self) {
+        unsafe { Header::cancel(self.ptr.as_ptr()) }
+    }
+}
+
+impl<R, M: Send + Sync + 'static> Future for JoinHandle<R, M> {
+    type Output = Option<R>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<R>> {
+        let header = self.header();
+        let mut registered = false;
+        loop {
+            let state = header.state.load(Ordering::Acquire);
+            if state & CLOSED != 0 {
+                return Poll::Ready(None);
+            }
+            if state & COMPLETED != 0 {
+                if header.state.fetch_or(CLOSED, Ordering::AcqRel) & CLOSED != 0 {
+                    // a cancel got there first and dropped it
+                    return Poll::Ready(None);
+                }
+                let mut output = MaybeUninit::<R>::uninit();
+                unsafe {
+                    (header.vtable.take_output)(
+                        self.ptr.as_ptr() as *const (),
+                        output.as_mut_ptr() as *mut (),
+                    );
+                    return Poll::Ready(Some(output.assume_init()));
+                }
+            }
+            if registered {
+                return Poll::Pending;
+            }
+            // check again after registering, the task may have finished meanwhile
+            *header.awaiter.lock() = Some(cx.waker().clone());
+            registered = true;
+        }
+    }
+}
+
+impl<R, M> Drop for JoinHandle<R, M> {
+    fn drop(&mut self) {
+        let header = unsafe { self.ptr.as_ref() };
+        let ptr = self.ptr.as_ptr() as *const ();
+        let prev = header.state.fetch_or(0, Ordering::Acquire);
+        if prev & (COMPLETED | CLOSED) == COMPLETED {
+            header.state.fetch_or(CLOSED, Ordering::AcqRel);
+            unsafe { (header.vtable.drop_stage)(ptr) };
+        }
+        if header.refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            unsafe { (header.vtable.dealloc)(ptr) };
+        }
+    }
+}
+
+/// An intrusive FIFO of runnable tasks, linked through their headers.
+pub struct TaskQueue<M> {
+    inner: Mutex<QueueInner<M>>,
+}
+
+struct QueueInner<M> {
+    head: *mut Header<M>,
+    tail: *mut Header<M>,
+    len: usize,
+}
+
+unsafe impl<M: Send + Sync> Send for TaskQueue<M> {}
+unsafe impl<M: Send + Sync> Sync for TaskQueue<M> {}
+
+impl<M> Default for TaskQueue<M> {
+    fn default() -> Self {
+        TaskQueue {
+            inner: Mutex::new(QueueInner {
+                head: ptr::null_mut(),
+                tail: ptr::null_mut(),
+                len: 0,
+            }),
+        }
+    }
+}
+
+impl<M> TaskQueue<M> {
+    pub fn new() -> Self {
+        TaskQueue::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Push `task` to the back.
+    pub fn push(&self, task: Task<M>) {
+        self.push_by(task, |_, _| false);
+    }
+
+    /// Insert `task` in front of the first queued task `t` with `before(task, t)`.
+    ///
+    /// The queue is kept sorted as long as every push uses the same order.
+    /// Pushing behind the tail is O(1), as is the common case of equal keys.
+    pub fn push_by(&self, task: Task<M>, before: impl Fn(&M, &M) -> bool) {
+        let node = task.into_raw();
+        let mut inner = self.inner.lock();
+        inner.len += 1;
+        unsafe {
+            let meta = &(*node).meta;
+            if inner.tail.is_null() || !before(meta, &(*inner.tail).meta) {
+                (*node).next.store(ptr::null_mut(), Ordering::Relaxed);
+                if inner.tail.is_null() {
+                    inner.head = node;
+                } else {
+                    (*inner.tail).next.store(node, Ordering::Relaxed);
+                }
+                inner.tail = node;
+                return;
+            }
+            // the tail runs after us, so the walk below always stops
+            let mut prev: *mut Header<M> = ptr::null_mut();
+            let mut cur = inner.head;
+            while !before(meta, &(*cur).meta) {
+                prev = cur;
+                cur = (*cur).next.load(Ordering::Relaxed);
+            }
+            (*node).next.store(cur, Ordering::Relaxed);
+            if prev.is_null() {
+                inner.head = node;
+            } else {
+                (*prev).next.store(node, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Pop the first task whose metadata passes `filter`.
+    pub fn pop_where(&self, filter: impl Fn(&M) -> bool) -> Option<Task<M>> {
+        let mut inner = self.inner.lock();
+        unsafe {
+            let mut prev: *mut Header<M> = ptr::null_mut();
+            let mut cur = inner.head;
+            while !cur.is_null() && !filter(&(*cur).meta) {
+                prev = cur;
+                cur = (*cur).next.load(Ordering::Relaxed);
+            }
+            if cur.is_null() {
+                return None;
+            }
+            let next = (*cur).next.load(Ordering::Relaxed);
+            if prev.is_null() {
+                inner.head = next;
+            } else {
+                (*prev).next.store(next, Ordering::Relaxed);
+            }
+            if inner.tail == cur {
+                inner.tail = prev;
+            }
+            inner.len -= 1;
+            (*cur).next.store(ptr::null_mut(), Ordering::Relaxed);
+            Some(Task::from_raw(cur))
+        }
+    }
+
+    /// Whether any queued task's metadata passes `filter`.
+    pub fn any_where(&self, filter: impl Fn(&M) -> bool) -> bool {
+        let inner = self.inner.lock();
+        let mut cur = inner.head;
+        unsafe {
+            while !cur.is_null() {
+                if filter(&(*cur).meta) {
+                    return true;
+                }
+                cur = (*cur).next.load(Ordering::Relaxed);
+            }
+        }
+        false
+    }
+
+    /// Pop the task at the front.
+    pub fn pop(&self) -> Option<Task<M>> {
+        self.pop_where(|_| true)
+    }
+}
+
+impl<M> Drop for TaskQueue<M> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asynchronous::test_util::Polled;
+    use alloc::{sync::Arc, vec::Vec};
+    use core::sync::atomic::AtomicBool;
+
+    /// Counts its drops.
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Drops of the future, of the output and of the task itself.
+    #[derive(Default)]
+    struct Drops {
+        future: Arc<AtomicUsize>,
+        output: Arc<AtomicUsize>,
+        task: Arc<AtomicUsize>,
+    }
+
+    impl Drops {
+        fn get(&self) -> (usize, usize, usize) {
+            let load = |n: &AtomicUsize| n.load(Ordering::Relaxed);
+            (load(&self.future), load(&self.output), load(&self.task))
+        }
+    }
+
+    /// A future running `f` on each poll, with its drops counted.
+    struct Probe<F> {
+        f: F,
+        _drop: Counted,
+    }
+
+    impl<F: FnMut(&mut Context) -> Poll<Counted> + Unpin> Future for Probe<F> {
+        type Output = Counted;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Counted> {
+            (self.f)(cx)
+        }
+    }
+
+    type Queue = Arc<TaskQueue<Counted>>;
+
+    fn spawn_probe<F>(drops: &Drops, f: F) -> (Queue, JoinHandle<Counted, Counted>)
+    where
+        F: FnMut(&mut Context) -> Poll<Counted> + Unpin + Send + 'static,
+    {
+        let queue = Arc::new(TaskQueue::new());
+        let ready = queue.clone();
+        let probe = Probe {
+            f,
+            _drop: Counted(drops.future.clone()),
+        };
+        let meta = Counted(drops.task.clone());
+        let (task, handle) = spawn(probe, move |task| ready.push(task), meta);
+        task.schedule();
+        (queue, handle)
+    }
+
+    fn run_all(queue: &Queue) -> usize {
+        let mut runs = 0;
+        while let Some(task) = queue.pop() {
+            task.run();
+            runs += 1;
+        }
+        runs
+    }
+
+    #[test]
+    fn output_goes_to_the_handle() {
+        let drops = Drops::default();
+        let output = drops.output.clone();
+        let (queue, handle) = spawn_probe(&drops, move |_| Poll::Ready(Counted(output.clone())));
+        let mut handle = Polled::new(handle);
+        assert!(handle.poll().is_none());
+        assert_eq!(run_all(&queue), 1);
+        assert!(handle.woken());
+        assert_eq!(drops.get(), (1, 0, 0));
+        drop(handle.poll().unwrap());
+        assert_eq!(drops.get(), (1, 1, 0));
+        drop(handle);
+        assert_eq!(drops.get(), (1, 1, 1));
+    }
+
+    #[test]
+    fn wake_during_poll_reschedules_once() {
+        let drops = Drops::default();
+        let output = drops.output.clone();
+        let mut polls = 0;
+        let (queue, handle) = spawn_probe(&drops, move |cx| {
+            polls += 1;
+            if polls == 2 {
+                return Poll::Ready(Counted(output.clone()));
+            }
+            cx.waker().wake_by_ref();
+            // by value too
+            let waker = cx.waker().clone();
+            waker.wake();
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        });
+        queue.pop().unwrap().run();
+        assert_eq!(queue.len(), 1);
+        assert_eq!(run_all(&queue), 1);
+        drop(handle);
+        assert_eq!(drops.get(), (1, 1, 1));
+    }
+
+    #[test]
+    fn cancel_while_idle() {
+        let drops = Drops::default();
+        let (queue, handle) = spawn_probe(&drops, |_| Poll::Pending);
+        assert_eq!(run_all(&queue), 1);
+        handle.cancel();
+        handle.cancel();
+        // scheduled once more to drop the future
+        assert_eq!(queue.len(), 1);
+        assert_eq!(run_all(&queue), 1);
+        assert_eq!(drops.get(), (1, 0, 0));
+        assert!(Polled::new(handle).poll().unwrap().is_none());
+        assert_eq!(drops.get(), (1, 0, 1));
+    }
+
+    #[test]
+    fn cancel_while_running() {
+        for &ready in [false, true].iter() {
+            let drops = Drops::default();
+            let output = drops.output.clone();
+            let slot = Arc::new(Mutex::new(None::<JoinHandle<Counted, Counted>>));
+            let handle = slot.clone();
+            let (queue, join) = spawn_probe(&drops, move |_| {
+                handle.lock().as_ref().unwrap().cancel();
+                match ready {
+                    true => Poll::Ready(Counted(output.clone())),
+                    false => Poll::Pending,
+                }
+            });
+            *slot.lock() = Some(join);
+            assert_eq!(run_all(&queue), 1);
+            // the future and any output are dropped right away
+            assert_eq!(drops.get(), (1, ready as usize, 0));
+            let join = slot.lock().take().unwrap();
+            assert!(Polled::new(join).poll().unwrap().is_none());
+            assert_eq!(drops.get(), (1, ready as usize, 1));
+        }
+    }
+
+    #[test]
+    fn racing_cancels_drop_the_output_once() {
+        for _ in 0..100 {
+            let drops = Drops::default();
+            let output = drops.output.clone();
+            let (queue, handle) =
+                spawn_probe(&drops, move |_| Poll::Ready(Counted(output.clone())));
+            run_all(&queue);
+            let handle = Arc::new(handle);
+            let go = Arc::new(AtomicBool::new(false));
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let (handle, go) = (handle.clone(), go.clone());
+                    std::thread::spawn(move || {
+                        while !go.load(Ordering::Acquire) {}
+                        handle.cancel();
+                    })
+                })
+                .collect();
+            go.store(true, Ordering::Release);
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(drops.get(), (1, 1, 0));
+        }
+    }
+
+    #[test]
+    fn handle_dropped_before_completion() {
+        let drops = Drops::default();
+        let output = drops.output.clone();
+        let (queue, handle) = spawn_probe(&drops, move |_| Poll::Ready(Counted(output.clone())));
+        drop(handle);
+        assert_eq!(drops.get(), (0, 0, 0));
+        // detached, it still runs
+        assert_eq!(run_all(&queue), 1);
+        assert_eq!(drops.get(), (1, 1, 1));
+    }
+
+    #[test]
+    fn handle_dropped_after_completion() {
+        let drops = Drops::default();
+        let output = drops.output.clone();
+        let (queue, handle) = spawn_probe(&drops, move |_| Poll::Ready(Counted(output.clone())));
+        assert_eq!(run_all(&queue), 1);
+        assert_eq!(drops.get(), (1, 0, 0));
+        drop(handle);
+        assert_eq!(drops.get(), (1, 1, 1));
+    }
+
+    #[test]
+    fn dropped_task_is_cancelled() {
+        let drops = Drops::default();
+        let (queue, handle) = spawn_probe(&drops, |_| Poll::Pending);
+        drop(queue.pop().unwrap());
+        assert_eq!(drops.get(), (1, 0, 0));
+        assert!(Polled::new(handle).poll().unwrap().is_none());
+        assert_eq!(drops.get(), (1, 0, 1));
+    }
+
+    #[test]
+    fn task_queue_order() {
+        let queue = Arc::new(TaskQueue::new());
+        let ready = queue.clone();
+        let mut handles = Vec::new();
+        for &key in [3, 1, 2, 1, 3, 0].iter() {
+            let ready = ready.clone();
+            let schedule = move |task| ready.push_by(task, |a: &(u8, usize), b| a.0 < b.0);
+            let (task, handle) = spawn(async {}, schedule, (key, handles.len()));
+            task.schedule();
+            handles.push(handle);
+        }
+        assert_eq!(queue.len(), 6);
+        assert!(queue.any_where(|&(key, _)| key == 2));
+        assert!(!queue.any_where(|&(key, _)| key == 4));
+        let odd = queue.pop_where(|&(key, _)| key % 2 == 1).unwrap();
+        assert_eq!(*odd.meta(), (1, 1));
+        let mut order = Vec::new();
+        while let Some(task) = queue.pop() {
+            order.push(*task.meta());
+        }
+        // sorted by key, in push order among equal keys
+        assert_eq!(order, [(0, 5), (1, 3), (2, 2), (3, 0), (3, 4)]);
+        assert!(queue.is_empty());
+        odd.run();
+    }
+}
@@ -0,0 +1,248 @@
+//! Deterministic simulation executor for host tests
+//!
+//! Time is virtual and only moves through [`SimExecutor::advance`].
+//! Among the ready tasks, the next one to poll is drawn from a seeded PRNG,
+//! so a failing interleaving is reproduced by running again with the same seed.
+
+use super::raw::{self, JoinHandle, Task};
+use super::timer::{Sleep, TimerQueue};
+use crate::rng::{Rng, XorShiftRng};
+use alloc::{sync::Arc, vec::Vec};
+use core::future::Future;
+use log::*;
+use spin::Mutex;
+
+/// The PRNG drawing the next task to poll.
+pub type SimRng = XorShiftRng;
+
+pub struct SimExecutor {
+    seed: u64,
+    rng: Mutex<SimRng>,
+    ready: Arc<Mutex<Vec<Task<()>>>>,
+    timers: Arc<TimerQueue>,
+}
+
+impl SimExecutor {
+    pub fn new(seed: u64) -> Self {
+        SimExecutor {
+            seed,
+            rng: Mutex::new(SimRng::new(seed)),
+            ready: Arc::new(Mutex::new(Vec::new())),
+            timers: Arc::new(TimerQueue::new()),
+        }
+    }
+
+    /// The seed this executor was created with.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn spawn<F>(&self, fut: F) -> JoinHandle<F::Output, ()>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let ready = self.ready.clone();
+        let schedule = move |task| ready.lock().push(task);
+        let (task, handle) = raw::spawn(fut, schedule, ());
+        task.schedule();
+        handle
+    }
+
+    /// Current virtual time.
+    pub fn now(&self) -> u64 {
+        self.timers.now()
+    }
+
+    /// The timer queue driven by this executor, which is also its clock.
+    pub fn timers(&self) -> &Arc<TimerQueue> {
+        &self.timers
+    }
+
+    /// Sleep for `ticks` of virtual time.
+    pub fn sleep(&self, ticks: u64) -> Sleep {
+        Sleep::until(self.timers.clone(), self.now() + ticks)
+    }
+
+    /// Sleep until virtual time `deadline`.
+    pub fn sleep_until(&self, deadline: u64) -> Sleep {
+        Sleep::until(self.timers.clone(), deadline)
+    }
+
+    /// Poll one ready task, chosen at random. Return false if none is ready.
+    pub fn step(&self) -> bool {
+        let task = {
+            let mut ready = self.ready.lock();
+            if ready.is_empty() {
+                return false;
+            }
+            let i = self.rng.lock().below(ready.len() as u64) as usize;
+            ready.swap_remove(i)
+        };
+        task.run();
+        true
+    }
+
+    /// Poll ready tasks until none is left, without moving time.
+    ///
+    /// Return the number of polls.
+    pub fn run_until_stalled(&self) -> usize {
+        let mut polls = 0;
+        while self.step() {
+            polls += 1;
+        }
+        polls
+    }
+
+    /// Move time forward by `ticks`, stopping at every timer deadline
+    /// on the way to let the woken tasks run.
+    pub fn advance(&self, ticks: u64) {
+        let target = self.now() + ticks;
+        self.run_until_stalled();
+        while let Some(deadline) = self.timers.next_deadline() {
+            if deadline > target {
+                break;
+            }
+            self.timers.expire(deadline);
+            self.run_until_stalled();
+        }
+        self.timers.expire(target);
+        self.run_until_stalled();
+        trace!("sim: seed {:#x} now {}", self.seed, self.now());
+    }
+
+    /// Run until no task is ready and no timer is pending,
+    /// jumping time straight to the next deadline whenever stalled.
+    pub fn run_until_idle(&self) {
+        self.run_until_stalled();
+        while let Some(deadline) = self.timers.next_deadline() {
+            self.timers.expire(deadline);
+            self.run_until_stalled();
+        }
+    }
+
+    /// Run the tasks until `fut` completes, jumping time to the next deadline
+    /// whenever stalled. Other tasks may still be alive when it returns.
+    ///
+    /// Return `None` if it deadlocked: nothing is ready, no timer is pending
+    /// and `fut` is still not finished.
+    pub fn block_on<F>(&self, fut: F) -> Option<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let output = Arc::new(Mutex::new(None));
+        let slot = output.clone();
+        self.spawn(async move {
+            let ret = fut.await;
+            *slot.lock() = Some(ret);
+        });
+        loop {
+            self.run_until_stalled();
+            if let Some(ret) = output.lock().take() {
+                return Some(ret);
+            }
+            let deadline = self.timers.next_deadline()?;
+            self.timers.expire(deadline);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asynchronous::periodic::{interval, MissedTicks};
+    use crate::asynchronous::stream::StreamExt;
+    use core::pin::Pin;
+    use core::task::{Context, Poll};
+
+    /// Pending once, so the task goes back to the ready set.
+    struct Yield(bool);
+
+    impl Future for Yield {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    /// The order in which 4 tasks yielding 4 times each are polled.
+    fn interleaving(seed: u64) -> Vec<usize> {
+        let sim = SimExecutor::new(seed);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for id in 0..4 {
+            let log = log.clone();
+            sim.spawn(async move {
+                for _ in 0..4 {
+                    log.lock().push(id);
+                    Yield(false).await;
+                }
+            });
+        }
+        sim.run_until_idle();
+        let log = log.lock().clone();
+        log
+    }
+
+    #[test]
+    fn seed_reproduces_interleaving() {
+        let first = interleaving(7);
+        assert_eq!(first.len(), 16);
+        assert_eq!(interleaving(7), first);
+        assert_ne!(interleaving(8), first);
+    }
+
+    #[test]
+    fn sleeps_wake_in_virtual_time_order() {
+        let sim = SimExecutor::new(1);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for &ticks in [30, 10, 20].iter() {
+            let sleep = sim.sleep(ticks);
+            let log = log.clone();
+            let timers = sim.timers().clone();
+            sim.spawn(async move {
+                sleep.await;
+                log.lock().push((ticks, timers.now()));
+            });
+        }
+        sim.advance(15);
+        assert_eq!(*log.lock(), [(10, 10)]);
+        sim.run_until_idle();
+        assert_eq!(*log.lock(), [(10, 10), (20, 20), (30, 30)]);
+        assert_eq!(sim.now(), 30);
+    }
+
+    #[test]
+    fn block_on_returns_while_periodic_task_runs() {
+        let sim = SimExecutor::new(2);
+        let ticks = Arc::new(Mutex::new(0));
+        let mut every_5 = interval(sim.timers().clone(), 5, MissedTicks::Skip);
+        let count = ticks.clone();
+        sim.spawn(async move {
+            while every_5.next().await.is_some() {
+                *count.lock() += 1;
+            }
+        });
+        let sleep = sim.sleep(12);
+        assert_eq!(
+            sim.block_on(async move {
+                sleep.await;
+                42
+            }),
+            Some(42)
+        );
+        assert_eq!(sim.now(), 12);
+        assert_eq!(*ticks.lock(), 2);
+    }
+
+    #[test]
+    fn block_on_reports_deadlock() {
+        let sim = SimExecutor::new(3);
+        assert_eq!(sim.block_on(core::future::pending::<()>()), None);
+    }
+}
@@ -0,0 +1,241 @@
+//! Executor statistics
+//!
+//! Counters are bumped with relaxed atomics on the hot path,
+//! and read back as a plain [`ExecutorStats`] snapshot.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// A monotonic time source used to measure wake-to-poll latency.
+pub trait Clock: Send + Sync {
+    /// Current time in platform-defined ticks.
+    fn now(&self) -> u64;
+}
+
+/// A clock that never advances, so every latency is recorded as zero.
+pub struct NoClock;
+
+impl Clock for NoClock {
+    fn now(&self) -> u64 {
+        0
+    }
+}
+
+/// Number of buckets in a [`LatencyHistogram`].
+pub const LATENCY_BUCKETS: usize = 16;
+
+/// Histogram of wake-to-poll latency with power-of-two buckets.
+///
+/// Bucket 0 counts zero latency, bucket `i` counts latencies in `[2^(i-1), 2^i)`,
+/// and the last bucket also takes everything beyond.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    pub buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// The bucket that `latency` falls into.
+    pub fn bucket_of(latency: u64) -> usize {
+        let bits = (64 - latency.leading_zeros()) as usize;
+        bits.min(LATENCY_BUCKETS - 1)
+    }
+
+    /// Total number of samples.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Upper bound (exclusive) of the bucket holding the `p`-th percentile,
+    /// `u64::MAX` if it is the last bucket, which has no bound,
+    /// or `None` if the histogram is empty.
+    pub fn percentile(&self, p: u8) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        // in hundredths of a sample, so that no rounding is needed
+        let target = (count * p.min(100) as u64).max(1);
+        let mut seen = 0;
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&n| {
+                seen += n;
+                seen * 100 >= target
+            })
+            .unwrap_or(LATENCY_BUCKETS - 1);
+        if bucket == LATENCY_BUCKETS - 1 {
+            Some(u64::MAX)
+        } else {
+            Some(1 << bucket)
+        }
+    }
+}
+
+/// A snapshot of executor counters.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ExecutorStats {
+    /// Tasks ever spawned.
+    pub spawned: usize,
+    /// Tasks whose future ran to completion.
+    pub completed: usize,
+    /// Tasks dropped before completion.
+    pub cancelled: usize,
+    /// Tasks that panicked and were contained.
+    pub failed: usize,
+    /// Total number of polls over all tasks.
+    pub polls: usize,
+    /// Tasks currently in the ready queue.
+    pub queue_depth: usize,
+    /// Highest ready queue depth ever observed.
+    pub max_queue_depth: usize,
+    /// Time between a task being woken and being polled.
+    pub wake_latency: LatencyHistogram,
+}
+
+/// The live counters behind [`ExecutorStats`].
+#[derive(Default)]
+pub(crate) struct Counters {
+    pub spawned: AtomicUsize,
+    pub completed: AtomicUsize,
+    pub cancelled: AtomicUsize,
+    pub failed: AtomicUsize,
+    pub polls: AtomicUsize,
+    pub queue_depth: AtomicUsize,
+    pub max_queue_depth: AtomicUsize,
+    pub wake_latency: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl Counters {
+    pub fn pushed(&self) {
+        let depth = self.queue_depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max_queue_depth.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    pub fn popped(&self, latency: u64) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.wake_latency[LatencyHistogram::bucket_of(latency)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ExecutorStats {
+        let mut wake_latency = LatencyHistogram::default();
+        for (dst, src) in wake_latency
+            .buckets
+            .iter_mut()
+            .zip(self.wake_latency.iter())
+        {
+            *dst = src.load(Ordering::Relaxed);
+        }
+        ExecutorStats {
+            spawned: self.spawned.load(Ordering::Relaxed),
+            completed: self.completed.load(Ordering::Relaxed),
+            cancelled: self.cancelled.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            polls: self.polls.load(Ordering::Relaxed),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            max_queue_depth: self.max_queue_depth.load(Ordering::Relaxed),
+            wake_latency,
+        }
+    }
+}
+
+/// Bumps `completed` when the task finishes, `failed` if it panics, or `cancelled` if it is dropped first.
+pub(crate) struct CompletionGuard<'a> {
+    counters: &'a Counters,
+    done: bool,
+}
+
+impl<'a> CompletionGuard<'a> {
+    pub fn new(counters: &'a Counters) -> Self {
+        counters.spawned.fetch_add(1, Ordering::Relaxed);
+        CompletionGuard {
+            counters,
+            done: false,
+        }
+    }
+
+    pub fn complete(mut self) {
+        self.done = true;
+        self.counters.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn fail(mut self) {
+        self.done = true;
+        self.counters.failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Drop for CompletionGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.counters.cancelled.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_powers_of_two() {
+        assert_eq!(LatencyHistogram::bucket_of(0), 0);
+        assert_eq!(LatencyHistogram::bucket_of(1), 1);
+        assert_eq!(LatencyHistogram::bucket_of(2), 2);
+        assert_eq!(LatencyHistogram::bucket_of(3), 2);
+        assert_eq!(LatencyHistogram::bucket_of(4), 3);
+        assert_eq!(LatencyHistogram::bucket_of(1 << 14), LATENCY_BUCKETS - 1);
+        assert_eq!(LatencyHistogram::bucket_of(u64::MAX), LATENCY_BUCKETS - 1);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentile(50), None);
+        // 90 samples of 0, 9 of 5 and 1 of 100
+        histogram.buckets[0] = 90;
+        histogram.buckets[LatencyHistogram::bucket_of(5)] = 9;
+        histogram.buckets[LatencyHistogram::bucket_of(100)] = 1;
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.percentile(0), Some(1));
+        assert_eq!(histogram.percentile(50), Some(1));
+        assert_eq!(histogram.percentile(90), Some(1));
+        assert_eq!(histogram.percentile(91), Some(8));
+        assert_eq!(histogram.percentile(99), Some(8));
+        assert_eq!(histogram.percentile(100), Some(128));
+    }
+
+    #[test]
+    fn overflow_bucket_has_no_bound() {
+        let mut histogram = LatencyHistogram::default();
+        histogram.buckets[0] = 1;
+        histogram.buckets[LatencyHistogram::bucket_of(1 << 20)] = 1;
+        assert_eq!(histogram.percentile(50), Some(1));
+        assert_eq!(histogram.percentile(100), Some(u64::MAX));
+    }
+
+    #[test]
+    fn counters_snapshot() {
+        let counters = Counters::default();
+        counters.pushed();
+        counters.pushed();
+        counters.popped(3);
+        CompletionGuard::new(&counters).complete();
+        CompletionGuard::new(&counters).fail();
+        drop(CompletionGuard::new(&counters));
+        let stats = counters.snapshot();
+        assert_eq!(stats.queue_depth, 1);
+        assert_eq!(stats.max_queue_depth, 2);
+        assert_eq!(stats.polls, 1);
+        assert_eq!(stats.wake_latency.buckets[2], 1);
+        assert_eq!(
+            (
+                stats.spawned,
+                stats.completed,
+                stats.failed,
+                stats.cancelled
+            ),
+            (3, 1, 1, 1)
+        );
+    }
+}
@@ -0,0 +1,426 @@
+//! Async streams and their combinators
+//!
+//! Combinators take their inner stream by value and require it to be `Unpin`;
+//! pin other streams with `Box::pin` first. Time-based combinators sleep on a
+//! [`TimerQueue`], usually [`timer::global`](super::timer::global).
+
+use super::timer::{Sleep, TimerQueue};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// A sequence of values produced asynchronously.
+pub trait Stream {
+    type Item;
+
+    /// Attempt to pull the next value, `None` once the stream is exhausted.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>>;
+}
+
+impl<S: Stream + Unpin + ?Sized> Stream for &mut S {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        Pin::new(&mut **self).poll_next(cx)
+    }
+}
+
+impl<S: Stream + ?Sized> Stream for Pin<Box<S>> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        self.get_mut().as_mut().poll_next(cx)
+    }
+}
+
+/// Combinators on top of [`Stream`].
+pub trait StreamExt: Stream {
+    /// The next value, `None` once the stream is exhausted.
+    fn next(&mut self) -> Next<'_, Self>
+    where
+        Self: Unpin,
+    {
+        Next { stream: self }
+    }
+
+    fn map<T, F>(self, f: F) -> Map<Self, F>
+    where
+        Self: Sized + Unpin,
+        F: FnMut(Self::Item) -> T,
+    {
+        Map { stream: self, f }
+    }
+
+    fn filter<F>(self, f: F) -> Filter<Self, F>
+    where
+        Self: Sized + Unpin,
+        F: FnMut(&Self::Item) -> bool,
+    {
+        Filter { stream: self, f }
+    }
+
+    /// At most `n` values.
+    fn take(self, n: usize) -> Take<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        Take {
+            stream: self,
+            remaining: n,
+        }
+    }
+
+    /// Yield values at least `period` ticks apart, delaying the early ones.
+    fn throttle(self, timers: Arc<TimerQueue>, period: u64) -> Throttle<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        Throttle {
+            stream: self,
+            timers,
+            period,
+            next_allowed: 0,
+            sleep: None,
+        }
+    }
+
+    /// Yield a value only once the stream stayed quiet for `quiet` ticks after it,
+    /// dropping the values superseded in the meantime.
+    fn debounce(self, timers: Arc<TimerQueue>, quiet: u64) -> Debounce<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        Debounce {
+            stream: self,
+            timers,
+            quiet,
+            pending: None,
+            sleep: None,
+            done: false,
+        }
+    }
+
+    /// Group values into chunks of at most `max`, yielding a partial chunk
+    /// once `timeout` ticks passed since its first value.
+    fn chunks_timeout(
+        self,
+        timers: Arc<TimerQueue>,
+        max: usize,
+        timeout: u64,
+    ) -> ChunksTimeout<Self>
+    where
+        Self: Sized + Unpin,
+    {
+        assert!(max > 0, "chunks_timeout: max must be positive");
+        ChunksTimeout {
+            stream: self,
+            timers,
+            max,
+            timeout,
+            buf: Vec::new(),
+            sleep: None,
+            done: false,
+        }
+    }
+}
+
+impl<S: Stream + ?Sized> StreamExt for S {}
+
+/// Poll an optional sleep, clearing it once it fires.
+fn poll_sleep(sleep: &mut Option<Sleep>, cx: &mut Context) -> Poll<()> {
+    match sleep {
+        Some(s) => match Pin::new(s).poll(cx) {
+            Poll::Ready(()) => {
+                *sleep = None;
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        },
+        None => Poll::Ready(()),
+    }
+}
+
+pub struct Next<'a, S: ?Sized> {
+    stream: &'a mut S,
+}
+
+impl<S: Stream + Unpin + ?Sized> Future for Next<'_, S> {
+    type Output = Option<S::Item>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+pub struct Map<S, F> {
+    stream: S,
+    f: F,
+}
+
+impl<T, S: Stream + Unpin, F: FnMut(S::Item) -> T + Unpin> Stream for Map<S, F> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.stream).poll_next(cx) {
+            Poll::Ready(item) => Poll::Ready(item.map(&mut this.f)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+pub struct Filter<S, F> {
+    stream: S,
+    f: F,
+}
+
+impl<S: Stream + Unpin, F: FnMut(&S::Item) -> bool + Unpin> Stream for Filter<S, F> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Ready(Some(item)) if !(this.f)(&item) => continue,
+                other => return other,
+            }
+        }
+    }
+}
+
+pub struct Take<S> {
+    stream: S,
+    remaining: usize,
+}
+
+impl<S: Stream + Unpin> Stream for Take<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        if this.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        let ret = Pin::new(&mut this.stream).poll_next(cx);
+        match ret {
+            Poll::Ready(Some(_)) => this.remaining -= 1,
+            Poll::Ready(None) => this.remaining = 0,
+            Poll::Pending => {}
+        }
+        ret
+    }
+}
+
+pub struct Throttle<S> {
+    stream: S,
+    timers: Arc<TimerQueue>,
+    period: u64,
+    next_allowed: u64,
+    sleep: Option<Sleep>,
+}
+
+impl<S: Stream + Unpin> Stream for Throttle<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        if this.sleep.is_none() && this.timers.now() < this.next_allowed {
+            this.sleep = Some(Sleep::until(this.timers.clone(), this.next_allowed));
+        }
+        if poll_sleep(&mut this.sleep, cx).is_pending() {
+            return Poll::Pending;
+        }
+        let ret = Pin::new(&mut this.stream).poll_next(cx);
+        if let Poll::Ready(Some(_)) = ret {
+            this.next_allowed = this.timers.now() + this.period;
+        }
+        ret
+    }
+}
+
+pub struct Debounce<S: Stream> {
+    stream: S,
+    timers: Arc<TimerQueue>,
+    quiet: u64,
+    pending: Option<S::Item>,
+    sleep: Option<Sleep>,
+    done: bool,
+}
+
+impl<S: Stream + Unpin> Stream for Debounce<S>
+where
+    S::Item: Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        let this = self.get_mut();
+        while !this.done {
+            match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    this.pending = Some(item);
+                    let deadline = this.timers.now() + this.quiet;
+                    this.sleep = Some(Sleep::until(this.timers.clone(), deadline));
+                }
+                // the last value goes out right away
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+        if this.done {
+            this.sleep = None;
+            return Poll::Ready(this.pending.take());
+        }
+        if this.pending.is_none() || poll_sleep(&mut this.sleep, cx).is_pending() {
+            return Poll::Pending;
+        }
+        Poll::Ready(this.pending.take())
+    }
+}
+
+pub struct ChunksTimeout<S: Stream> {
+    stream: S,
+    timers: Arc<TimerQueue>,
+    max: usize,
+    timeout: u64,
+    buf: Vec<S::Item>,
+    sleep: Option<Sleep>,
+    done: bool,
+}
+
+impl<S: Stream + Unpin> Stream for ChunksTimeout<S>
+where
+    S::Item: Unpin,
+{
+    type Item = Vec<S::Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Vec<S::Item>>> {
+        let this = self.get_mut();
+        while !this.done {
+            match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Ready(Some(item)) => {
+                    if this.buf.is_empty() {
+                        let deadline = this.timers.now() + this.timeout;
+                        this.sleep = Some(Sleep::until(this.timers.clone(), deadline));
+                    }
+                    this.buf.push(item);
+                    if this.buf.len() == this.max {
+                        this.sleep = None;
+                        return Poll::Ready(Some(mem::take(&mut this.buf)));
+                    }
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+        if this.buf.is_empty() {
+            return if this.done {
+                Poll::Ready(None)
+            } else {
+                Poll::Pending
+            };
+        }
+        if !this.done && poll_sleep(&mut this.sleep, cx).is_pending() {
+            return Poll::Pending;
+        }
+        this.sleep = None;
+        Poll::Ready(Some(mem::take(&mut this.buf)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asynchronous::sim::SimExecutor;
+    use alloc::collections::VecDeque;
+
+    /// Yields each value at its virtual time, then ends.
+    struct Scripted {
+        timers: Arc<TimerQueue>,
+        script: VecDeque<(u64, usize)>,
+        sleep: Option<Sleep>,
+    }
+
+    fn scripted(sim: &SimExecutor, times: &[u64]) -> Scripted {
+        Scripted {
+            timers: sim.timers().clone(),
+            script: times.iter().copied().zip(0..).collect(),
+            sleep: None,
+        }
+    }
+
+    impl Stream for Scripted {
+        type Item = usize;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<usize>> {
+            let this = self.get_mut();
+            let (at, value) = match this.script.front() {
+                Some(&next) => next,
+                None => return Poll::Ready(None),
+            };
+            if this.sleep.is_none() && this.timers.now() < at {
+                this.sleep = Some(Sleep::until(this.timers.clone(), at));
+            }
+            if poll_sleep(&mut this.sleep, cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.script.pop_front();
+            Poll::Ready(Some(value))
+        }
+    }
+
+    /// Every item of `stream` with the virtual time it came out.
+    fn collect<S>(sim: &SimExecutor, mut stream: S) -> Vec<(S::Item, u64)>
+    where
+        S: Stream + Unpin + Send + 'static,
+        S::Item: Send + 'static,
+    {
+        let timers = sim.timers().clone();
+        let out = sim.block_on(async move {
+            let mut out = Vec::new();
+            while let Some(item) = stream.next().await {
+                out.push((item, timers.now()));
+            }
+            out
+        });
+        out.unwrap()
+    }
+
+    #[test]
+    fn throttle_spaces_values() {
+        let sim = SimExecutor::new(1);
+        let stream = scripted(&sim, &[0, 1, 2, 10, 30]).throttle(sim.timers().clone(), 5);
+        let out = collect(&sim, stream);
+        assert_eq!(out, [(0, 0), (1, 5), (2, 10), (3, 15), (4, 30)]);
+    }
+
+    #[test]
+    fn debounce_keeps_the_last_of_a_burst() {
+        let sim = SimExecutor::new(1);
+        let stream = scripted(&sim, &[0, 1, 2, 10, 11, 30]).debounce(sim.timers().clone(), 3);
+        let out = collect(&sim, stream);
+        // the last value goes out as the stream ends
+        assert_eq!(out, [(2, 5), (4, 14), (5, 30)]);
+    }
+
+    #[test]
+    fn chunks_by_size_or_timeout() {
+        let sim = SimExecutor::new(1);
+        let stream = scripted(&sim, &[0, 1, 2, 3, 20]).chunks_timeout(sim.timers().clone(), 3, 5);
+        let out = collect(&sim, stream);
+        assert_eq!(out, [(vec![0, 1, 2], 2), (vec![3], 8), (vec![4], 20)]);
+    }
+
+    #[test]
+    fn map_filter_take() {
+        let sim = SimExecutor::new(1);
+        let stream = scripted(&sim, &[0, 0, 0, 0, 0, 0])
+            .filter(|v| v % 2 == 0)
+            .map(|v| v * 10)
+            .take(2);
+        let out = collect(&sim, stream);
+        assert_eq!(out, [(0, 0), (20, 0)]);
+    }
+}
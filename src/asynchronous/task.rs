@@ -0,0 +1,195 @@
+//! Task metadata shared between the executor, the task and the task registry.
+
+use super::await_tree::AwaitTree;
+use crate::scheduler::CpuMask;
+use alloc::string::String;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// Unique identifier of a spawned task.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TaskId(usize);
+
+impl TaskId {
+    fn next() -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_usize(self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Life cycle of a task as seen by the executor.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TaskState {
+    /// Sitting in the ready queue.
+    Scheduled = 0,
+    /// Being polled.
+    Running = 1,
+    /// Pending, waiting to be woken.
+    Idle = 2,
+    /// The future has run to completion.
+    Completed = 3,
+    /// The future panicked and was contained.
+    Failed = 4,
+}
+
+impl TaskState {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => TaskState::Scheduled,
+            1 => TaskState::Running,
+            2 => TaskState::Idle,
+            3 => TaskState::Completed,
+            _ => TaskState::Failed,
+        }
+    }
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            TaskState::Scheduled => "scheduled",
+            TaskState::Running => "running",
+            TaskState::Idle => "idle",
+            TaskState::Completed => "completed",
+            TaskState::Failed => "failed",
+        };
+        f.pad(s)
+    }
+}
+
+const NO_DEADLINE: u64 = u64::MAX;
+
+/// Bookkeeping carried along with every task.
+pub struct TaskInfo {
+    id: TaskId,
+    name: Option<String>,
+    priority: u8,
+    affinity: CpuMask,
+    /// `NO_DEADLINE` for a best-effort task.
+    deadline: AtomicU64,
+    /// Whether the current deadline was missed.
+    missed: AtomicBool,
+    state: AtomicU8,
+    polls: AtomicUsize,
+    woken_at: AtomicU64,
+    await_tree: AwaitTree,
+}
+
+impl TaskInfo {
+    pub(crate) fn new(
+        name: Option<String>,
+        priority: u8,
+        affinity: CpuMask,
+        deadline: Option<u64>,
+    ) -> Self {
+        TaskInfo {
+            id: TaskId::next(),
+            name,
+            priority,
+            affinity,
+            deadline: AtomicU64::new(deadline.unwrap_or(NO_DEADLINE)),
+            missed: AtomicBool::new(false),
+            state: AtomicU8::new(TaskState::Idle as u8),
+            polls: AtomicUsize::new(0),
+            woken_at: AtomicU64::new(0),
+            await_tree: AwaitTree::new(),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Among best-effort tasks, higher priorities are polled first.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// The CPUs allowed to poll the task.
+    pub fn affinity(&self) -> CpuMask {
+        self.affinity
+    }
+
+    /// Absolute deadline, or `None` for a best-effort task.
+    pub fn deadline(&self) -> Option<u64> {
+        match self.deadline.load(Ordering::Relaxed) {
+            NO_DEADLINE => None,
+            deadline => Some(deadline),
+        }
+    }
+
+    pub fn state(&self) -> TaskState {
+        TaskState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    /// Number of times the task has been polled.
+    pub fn polls(&self) -> usize {
+        self.polls.load(Ordering::Relaxed)
+    }
+
+    /// Clock time of the last wakeup.
+    pub fn woken_at(&self) -> u64 {
+        self.woken_at.load(Ordering::Relaxed)
+    }
+
+    /// The await points the task was pending on after its last poll.
+    pub fn await_tree(&self) -> &AwaitTree {
+        &self.await_tree
+    }
+
+    /// Give the next job of the task its own deadline, whose miss is reported afresh.
+    pub(crate) fn set_deadline(&self, deadline: u64) {
+        self.deadline.store(deadline, Ordering::Relaxed);
+        self.missed.store(false, Ordering::Relaxed);
+    }
+
+    /// Return true the first time the task is found past its current deadline at `now`.
+    pub(crate) fn check_deadline(&self, now: u64) -> bool {
+        match self.deadline() {
+            Some(deadline) if now > deadline => !self.missed.swap(true, Ordering::Relaxed),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn set_state(&self, state: TaskState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Called when the task is pushed to the ready queue at time `now`.
+    pub(crate) fn woken(&self, now: u64) {
+        self.woken_at.store(now, Ordering::Relaxed);
+        self.set_state(TaskState::Scheduled);
+    }
+
+    /// Called right before the task is polled.
+    pub(crate) fn poll_start(&self) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.set_state(TaskState::Running);
+    }
+
+    /// Called after the task is polled.
+    ///
+    /// A task woken during its own poll stays scheduled.
+    pub(crate) fn poll_end(&self) {
+        let running = TaskState::Running as u8;
+        let idle = TaskState::Idle as u8;
+        let _ = self
+            .state
+            .compare_exchange(running, idle, Ordering::AcqRel, Ordering::Acquire);
+    }
+}
@@ -0,0 +1,58 @@
+//! Polling futures by hand in unit tests.
+
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+static FLAG_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(flag_clone, flag_wake, flag_wake_by_ref, flag_drop);
+
+unsafe fn flag_clone(ptr: *const ()) -> RawWaker {
+    let flag = Arc::from_raw(ptr as *const AtomicBool);
+    core::mem::forget(flag.clone());
+    RawWaker::new(Arc::into_raw(flag) as *const (), &FLAG_VTABLE)
+}
+
+unsafe fn flag_wake(ptr: *const ()) {
+    flag_wake_by_ref(ptr);
+    flag_drop(ptr);
+}
+
+unsafe fn flag_wake_by_ref(ptr: *const ()) {
+    (*(ptr as *const AtomicBool)).store(true, Ordering::Relaxed);
+}
+
+unsafe fn flag_drop(ptr: *const ()) {
+    drop(Arc::from_raw(ptr as *const AtomicBool));
+}
+
+/// A future polled by hand, with the flag its waker raises.
+pub struct Polled<F> {
+    pub fut: F,
+    woken: Arc<AtomicBool>,
+}
+
+impl<F: Future + Unpin> Polled<F> {
+    pub fn new(fut: F) -> Self {
+        Polled {
+            fut,
+            woken: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn poll(&mut self) -> Option<F::Output> {
+        let flag = Arc::into_raw(self.woken.clone()) as *const ();
+        let waker = unsafe { Waker::from_raw(RawWaker::new(flag, &FLAG_VTABLE)) };
+        match Pin::new(&mut self.fut).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(ret) => Some(ret),
+            Poll::Pending => None,
+        }
+    }
+
+    /// Whether it was woken since the last call.
+    pub fn woken(&self) -> bool {
+        self.woken.swap(false, Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,158 @@
+//! Timer queue and `Sleep` future
+//!
+//! The queue keeps the time it was last told about through [`TimerQueue::expire`],
+//! which is called from the timer interrupt or by the simulation executor.
+
+use super::stats::Clock;
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use lazy_static::*;
+use spin::Mutex;
+
+/// Timers are ordered by deadline, then by registration order.
+type TimerKey = (u64, u64);
+
+#[derive(Default)]
+pub struct TimerQueue {
+    now: AtomicU64,
+    inner: Mutex<TimerQueueInner>,
+}
+
+#[derive(Default)]
+struct TimerQueueInner {
+    seq: u64,
+    timers: BTreeMap<TimerKey, Waker>,
+}
+
+impl TimerQueue {
+    pub fn new() -> Self {
+        TimerQueue::default()
+    }
+
+    /// The time passed to the last `expire`.
+    pub fn now(&self) -> u64 {
+        self.now.load(Ordering::Acquire)
+    }
+
+    /// The earliest pending deadline.
+    pub fn next_deadline(&self) -> Option<u64> {
+        let inner = self.inner.lock();
+        inner.timers.keys().next().map(|&(deadline, _)| deadline)
+    }
+
+    /// Advance the time to `now` and wake every timer due by then.
+    ///
+    /// Return the number of timers fired. Time never goes backwards.
+    pub fn expire(&self, now: u64) -> usize {
+        self.now.fetch_max(now, Ordering::AcqRel);
+        let now = self.now();
+        let mut fired = 0;
+        loop {
+            // wake outside the lock, the waker may register a new timer
+            let waker = {
+                let mut inner = self.inner.lock();
+                match inner.timers.keys().next() {
+                    Some(&key) if key.0 <= now => inner.timers.remove(&key),
+                    _ => None,
+                }
+            };
+            match waker {
+                Some(waker) => {
+                    waker.wake();
+                    fired += 1;
+                }
+                None => return fired,
+            }
+        }
+    }
+
+    fn register(&self, deadline: u64, waker: Waker) -> TimerKey {
+        let mut inner = self.inner.lock();
+        let key = (deadline, inner.seq);
+        inner.seq += 1;
+        inner.timers.insert(key, waker);
+        key
+    }
+
+    fn cancel(&self, key: TimerKey) {
+        self.inner.lock().timers.remove(&key);
+    }
+}
+
+impl Clock for TimerQueue {
+    fn now(&self) -> u64 {
+        TimerQueue::now(self)
+    }
+}
+
+/// Future that completes once the queue's time reaches `deadline`.
+pub struct Sleep {
+    timers: Arc<TimerQueue>,
+    deadline: u64,
+    key: Option<TimerKey>,
+}
+
+impl Sleep {
+    pub fn until(timers: Arc<TimerQueue>, deadline: u64) -> Self {
+        Sleep {
+            timers,
+            deadline,
+            key: None,
+        }
+    }
+
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if let Some(key) = self.key.take() {
+            self.timers.cancel(key);
+        }
+        if self.timers.now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let key = self.timers.register(self.deadline, cx.waker().clone());
+        // the deadline may have passed before we registered
+        if self.timers.now() >= self.deadline {
+            self.timers.cancel(key);
+            return Poll::Ready(());
+        }
+        self.key = Some(key);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.timers.cancel(key);
+        }
+    }
+}
+
+lazy_static! {
+    static ref GLOBAL_TIMERS: Arc<TimerQueue> = Arc::new(TimerQueue::new());
+}
+
+/// The kernel timer queue, driven by the timer interrupt through [`expire`].
+pub fn global() -> Arc<TimerQueue> {
+    GLOBAL_TIMERS.clone()
+}
+
+/// Advance the kernel timer queue to `now`. Called from the timer interrupt.
+pub fn expire(now: u64) -> usize {
+    GLOBAL_TIMERS.expire(now)
+}
+
+/// Sleep for `ticks` on the kernel timer queue.
+pub fn sleep(ticks: u64) -> Sleep {
+    Sleep::until(global(), GLOBAL_TIMERS.now() + ticks)
+}
@@ -0,0 +1,167 @@
+//! Panic containment for task polls
+//!
+//! With `panic = "unwind"` a contained task that panics is stopped at the poll
+//! boundary. Elsewhere a panic can not be caught, and aborts whatever the policy.
+//!
+//! With the `std` feature, the panic is caught by `std::panic::catch_unwind`, which also
+//! resets the panic count, so that the next panic is not taken for a double panic.
+//! Without it, panics are only caught with the `unwind` feature, which needs a nightly
+//! that still has `core::intrinsics::r#try`, and the payload is handed back to the
+//! panic runtime to be freed.
+
+use super::executor::{FailurePolicy, JoinError};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+/// Poll `fut`, returning `None` if it panicked.
+#[cfg(any(test, feature = "std"))]
+fn catch_poll<F: Future>(fut: Pin<&mut F>, cx: &mut Context) -> Option<Poll<F::Output>> {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    // the future is never polled again once it panicked
+    catch_unwind(AssertUnwindSafe(|| fut.poll(cx))).ok()
+}
+
+/// Poll `fut`, returning `None` if it panicked.
+#[cfg(all(feature = "unwind", not(any(test, feature = "std"))))]
+fn catch_poll<F: Future>(fut: Pin<&mut F>, cx: &mut Context) -> Option<Poll<F::Output>> {
+    use alloc::boxed::Box;
+    use core::any::Any;
+
+    #[allow(improper_ctypes)]
+    extern "C" {
+        /// Provided by the panic runtime: turn the exception caught into its payload.
+        fn __rust_panic_cleanup(payload: *mut u8) -> *mut (dyn Any + Send + 'static);
+    }
+
+    struct Data<'a, 'b, 'c, F: Future> {
+        fut: Option<Pin<&'a mut F>>,
+        cx: &'b mut Context<'c>,
+        ret: Option<Poll<F::Output>>,
+    }
+
+    fn do_poll<F: Future>(data: *mut u8) {
+        let data = unsafe { &mut *(data as *mut Data<F>) };
+        let fut = data.fut.take().unwrap();
+        data.ret = Some(fut.poll(data.cx));
+    }
+
+    fn do_catch(_data: *mut u8, payload: *mut u8) {
+        drop(unsafe { Box::from_raw(__rust_panic_cleanup(payload)) });
+    }
+
+    let mut data = Data {
+        fut: Some(fut),
+        cx,
+        ret: None,
+    };
+    let ptr = &mut data as *mut Data<F> as *mut u8;
+    match unsafe { core::intrinsics::r#try(do_poll::<F>, ptr, do_catch) } {
+        0 => data.ret,
+        _ => None,
+    }
+}
+
+#[cfg(not(any(test, feature = "std", feature = "unwind")))]
+fn catch_poll<F: Future>(fut: Pin<&mut F>, cx: &mut Context) -> Option<Poll<F::Output>> {
+    Some(fut.poll(cx))
+}
+
+/// Resolves to `Err(JoinError::Panicked)` if a contained future panics.
+pub(crate) struct Contained<F> {
+    fut: F,
+    policy: FailurePolicy,
+    panicked: bool,
+}
+
+impl<F: Future> Contained<F> {
+    pub fn new(fut: F, policy: FailurePolicy) -> Self {
+        Contained {
+            fut,
+            policy,
+            panicked: false,
+        }
+    }
+}
+
+impl<F: Future> Future for Contained<F> {
+    type Output = Result<F::Output, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // safety: `fut` is structurally pinned and never moved
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+        // a panicked future is in an unknown state, never poll it again
+        if this.panicked {
+            return Poll::Ready(Err(JoinError::Panicked));
+        }
+        let ret = match this.policy {
+            FailurePolicy::Abort => Some(fut.poll(cx)),
+            FailurePolicy::Contain => catch_poll(fut, cx),
+        };
+        match ret {
+            Some(poll) => poll.map(Ok),
+            None => {
+                this.panicked = true;
+                Poll::Ready(Err(JoinError::Panicked))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asynchronous::executor::{Builder, Executor};
+    use crate::asynchronous::task::TaskState;
+    use alloc::{boxed::Box, sync::Arc};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A panic payload counting its drops.
+    struct Payload(Arc<AtomicUsize>);
+
+    impl Drop for Payload {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn payload_is_freed() {
+        let _cpu = crate::asynchronous::executor::tests::CPU0.lock();
+        let ex: &'static Executor = Box::leak(Box::new(Executor::new()));
+        let drops = Arc::new(AtomicUsize::new(0));
+        let payload = Payload(drops.clone());
+        let handle = Builder::new()
+            .failure_policy(FailurePolicy::Contain)
+            .spawn_on(
+                ex,
+                async move { std::panic::resume_unwind(Box::new(payload)) },
+            );
+        while ex.run_once() {}
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        assert_eq!(handle.info().state(), TaskState::Failed);
+        let waiter = ex.spawn(async move {
+            assert_eq!(handle.await, Err(JoinError::Panicked));
+        });
+        while ex.run_once() {}
+        assert_eq!(waiter.info().state(), TaskState::Completed);
+    }
+
+    #[test]
+    fn panics_in_a_row_are_contained() {
+        let _cpu = crate::asynchronous::executor::tests::CPU0.lock();
+        let ex: &'static Executor = Box::leak(Box::new(Executor::new()));
+        let contained = || Builder::new().failure_policy(FailurePolicy::Contain);
+        let first = contained().spawn_on(ex, async { panic!("first") });
+        let second = contained().spawn_on(ex, async { panic!("second") });
+        while ex.run_once() {}
+        let waiter = ex.spawn(async move {
+            assert_eq!(first.await, Err(JoinError::Panicked));
+            assert_eq!(second.await, Err(JoinError::Panicked));
+        });
+        while ex.run_once() {}
+        assert_eq!(waiter.info().state(), TaskState::Completed);
+        assert_eq!(ex.stats().failed, 2);
+    }
+}
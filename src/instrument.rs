@@ -0,0 +1,60 @@
+//! Instrumentation hooks for executor and scheduler events
+//!
+//! Every callback defaults to a no-op, so a tracer only overrides what it needs.
+//! Executors and schedulers are generic over their [`Instrument`],
+//! and the default [`NoInstrument`] compiles away entirely.
+//!
+//! Executor events carry task ids, scheduler events carry thread ids.
+
+/// Receiver of executor and scheduler events.
+pub trait Instrument: Send + Sync + 'static {
+    /// A task is spawned.
+    fn on_spawn(&self, _task: usize) {}
+    /// A task is woken.
+    fn on_wake(&self, _task: usize) {}
+    /// A task or thread is pushed to a ready queue.
+    fn on_push(&self, _id: usize) {}
+    /// A task or thread is popped from a ready queue.
+    fn on_pop(&self, _id: usize) {}
+    /// The executor starts polling a task.
+    fn on_poll_start(&self, _task: usize) {}
+    /// The executor finished polling a task. `ready` is true if it completed.
+    fn on_poll_end(&self, _task: usize, _ready: bool) {}
+    /// A scheduler got a tick for the running thread.
+    fn on_tick(&self, _tid: usize, _need_reschedule: bool) {}
+    /// A scheduler picked `tid` to run on `cpu_id`.
+    fn on_switch(&self, _cpu_id: usize, _tid: usize) {}
+}
+
+/// The default instrument, which ignores every event.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoInstrument;
+
+impl Instrument for NoInstrument {}
+
+impl<I: Instrument + ?Sized> Instrument for &'static I {
+    fn on_spawn(&self, task: usize) {
+        (**self).on_spawn(task)
+    }
+    fn on_wake(&self, task: usize) {
+        (**self).on_wake(task)
+    }
+    fn on_push(&self, id: usize) {
+        (**self).on_push(id)
+    }
+    fn on_pop(&self, id: usize) {
+        (**self).on_pop(id)
+    }
+    fn on_poll_start(&self, task: usize) {
+        (**self).on_poll_start(task)
+    }
+    fn on_poll_end(&self, task: usize, ready: bool) {
+        (**self).on_poll_end(task, ready)
+    }
+    fn on_tick(&self, tid: usize, need_reschedule: bool) {
+        (**self).on_tick(tid, need_reschedule)
+    }
+    fn on_switch(&self, cpu_id: usize, tid: usize) {
+        (**self).on_switch(cpu_id, tid)
+    }
+}
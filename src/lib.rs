@@ -1,11 +1,22 @@
-#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![feature(linkage)]
 #![feature(llvm_asm)]
 #![feature(naked_functions)]
 #![feature(global_asm)]
+#![cfg_attr(
+    all(feature = "unwind", not(any(test, feature = "std"))),
+    feature(core_intrinsics)
+)]
 #![deny(warnings)]
 
 extern crate alloc;
+// lets `#[traced]` name this crate from inside it
+extern crate self as rcore_thread;
 
 pub mod asynchronous;
+pub mod instrument;
+pub mod platform;
+pub mod rng;
 pub mod scheduler;
+
+pub use rcore_thread_macros::traced;
@@ -0,0 +1,139 @@
+//! Platform hooks and idle CPU tracking
+//!
+//! A CPU with nothing to run halts until the next interrupt. When work is enqueued for it
+//! from another CPU, the executor and the schedulers send it a reschedule IPI,
+//! but only if it is marked idle here, so busy CPUs are never interrupted.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::*;
+use spin::Once;
+
+/// What the kernel provides about the machine.
+pub trait Platform: Sync {
+    /// Id of the running CPU.
+    fn cpu_id(&self) -> usize;
+    /// Interrupt `cpu`, so that it leaves its halt and looks for work again.
+    fn send_reschedule_ipi(&self, cpu: usize);
+}
+
+static PLATFORM: Once<&'static dyn Platform> = Once::new();
+
+/// Register the platform hooks. Only the first call takes effect.
+///
+/// Without them, every CPU is considered to be CPU 0 and no IPI is sent.
+pub fn set_platform(platform: &'static dyn Platform) {
+    PLATFORM.call_once(|| platform);
+}
+
+/// Id of the running CPU.
+pub fn cpu_id() -> usize {
+    PLATFORM.r#try().map_or(0, |platform| platform.cpu_id())
+}
+
+/// Bitmap of idle CPUs. Supports as many CPUs as `usize` has bits.
+pub struct IdleCpus(AtomicUsize);
+
+/// The bit of `cpu` in the bitmap.
+fn bit(cpu: usize) -> usize {
+    let limit = usize::MAX.count_ones() as usize;
+    assert!(
+        cpu < limit,
+        "idle cpus: cpu{} over the limit of {}",
+        cpu,
+        limit
+    );
+    1 << cpu
+}
+
+impl Default for IdleCpus {
+    fn default() -> Self {
+        IdleCpus::new()
+    }
+}
+
+impl IdleCpus {
+    pub const fn new() -> Self {
+        IdleCpus(AtomicUsize::new(0))
+    }
+
+    /// Mark `cpu` idle, right before it halts.
+    pub fn enter(&self, cpu: usize) {
+        self.0.fetch_or(bit(cpu), Ordering::SeqCst);
+    }
+
+    /// Mark `cpu` busy, right after it wakes up.
+    pub fn leave(&self, cpu: usize) {
+        self.0.fetch_and(!bit(cpu), Ordering::SeqCst);
+    }
+
+    pub fn is_idle(&self, cpu: usize) -> bool {
+        self.0.load(Ordering::SeqCst) & bit(cpu) != 0
+    }
+
+    /// Clear the idle bit of `cpu`, returning true if it was set.
+    ///
+    /// Only the caller that clears the bit sends the IPI.
+    fn take(&self, cpu: usize) -> bool {
+        self.0.fetch_and(!bit(cpu), Ordering::SeqCst) & bit(cpu) != 0
+    }
+
+    /// Clear the idle bit of any CPU set in `allowed` other than `except`, returning it.
+    fn take_any(&self, allowed: usize, except: usize) -> Option<usize> {
+        loop {
+            let mask = self.0.load(Ordering::SeqCst) & allowed & !bit(except);
+            if mask == 0 {
+                return None;
+            }
+            let cpu = mask.trailing_zeros() as usize;
+            if self.take(cpu) {
+                return Some(cpu);
+            }
+        }
+    }
+}
+
+static IDLE_CPUS: IdleCpus = IdleCpus::new();
+
+/// The idle CPUs of the machine.
+pub fn idle_cpus() -> &'static IdleCpus {
+    &IDLE_CPUS
+}
+
+fn send_ipi(cpu: usize) {
+    if let Some(platform) = PLATFORM.r#try() {
+        trace!("reschedule ipi -> cpu{}", cpu);
+        platform.send_reschedule_ipi(cpu);
+    }
+}
+
+/// Wake `cpu` if it is idle and is not the running CPU.
+///
+/// Return true if an IPI was sent.
+pub fn wake_cpu(cpu: usize) -> bool {
+    if cpu == cpu_id() || !IDLE_CPUS.take(cpu) {
+        return false;
+    }
+    send_ipi(cpu);
+    true
+}
+
+/// Wake one idle CPU other than the running one, for new work anyone can pick up.
+///
+/// Return true if an IPI was sent.
+pub fn wake_idle_cpu() -> bool {
+    wake_idle_cpu_in(usize::MAX)
+}
+
+/// Wake one idle CPU set in the bitmap `allowed` other than the running one,
+/// for new work only those CPUs may pick up.
+///
+/// Return true if an IPI was sent.
+pub fn wake_idle_cpu_in(allowed: usize) -> bool {
+    match IDLE_CPUS.take_any(allowed, cpu_id()) {
+        Some(cpu) => {
+            send_ipi(cpu);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,59 @@
+//! Pseudo random numbers for randomized scheduling and simulation
+
+/// A source of pseudo random numbers.
+pub trait Rng: Send + 'static {
+    fn next_u64(&mut self) -> u64;
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+}
+
+/// xorshift64*, small and good enough to shuffle schedules.
+#[derive(Debug, Clone)]
+pub struct XorShiftRng(u64);
+
+impl XorShiftRng {
+    pub fn new(seed: u64) -> Self {
+        // the state must never be zero, or it stays zero
+        match seed ^ 0x9E37_79B9_7F4A_7C15 {
+            0 => XorShiftRng(0x2545_F491_4F6C_DD1D),
+            state => XorShiftRng(state),
+        }
+    }
+}
+
+impl Rng for XorShiftRng {
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn sequence(seed: u64) -> Vec<u64> {
+        let mut rng = XorShiftRng::new(seed);
+        (0..16).map(|_| rng.next_u64()).collect()
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        assert_eq!(sequence(42), sequence(42));
+        assert_ne!(sequence(42), sequence(43));
+    }
+
+    #[test]
+    fn no_seed_sticks_at_zero() {
+        let mut rng = XorShiftRng::new(0x9E37_79B9_7F4A_7C15);
+        assert!((0..16).any(|_| rng.next_u64() != 0));
+    }
+}
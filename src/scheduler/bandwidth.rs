@@ -0,0 +1,417 @@
+//! CPU bandwidth control
+//!
+//! Wraps any scheduler to cap the CPU time of groups of threads: a group may run
+//! `quota` ticks every `period` ticks, summed over its threads and CPUs.
+//! Once the quota is used up, the group is throttled: its ready threads are taken out of
+//! the wrapped scheduler, and given back when the next period refills the quota.
+//! Threads in no group are never throttled.
+//!
+//! Each tick charges one unit of quota. Periods are measured on the clock given
+//! at construction, which should count in the same unit, and are also refilled
+//! on `pop`, so a throttled group comes back while every CPU idles.
+
+use super::*;
+use crate::asynchronous::stats::Clock;
+use spin::MutexGuard;
+
+/// A group of threads sharing a CPU quota.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BandwidthGroupId(usize);
+
+/// Throttling history of a group, like `cpu.stat` of cgroups.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ThrottleStats {
+    /// Periods elapsed since the group was created.
+    pub nr_periods: u64,
+    /// Periods in which the group was throttled.
+    pub nr_throttled: u64,
+    /// Ticks the group spent throttled.
+    pub throttled_ticks: u64,
+}
+
+pub struct BandwidthScheduler<S: Scheduler, I: Instrument = NoInstrument> {
+    inner: Mutex<BandwidthSchedulerInner>,
+    scheduler: S,
+    clock: &'static dyn Clock,
+    instrument: I,
+}
+
+struct BandwidthSchedulerInner {
+    /// Clock time when the lock was last taken.
+    now: u64,
+    groups: Vec<BandwidthGroup>,
+    infos: Vec<BandwidthProcInfo>,
+}
+
+struct BandwidthGroup {
+    quota: u64,
+    period: u64,
+    /// Quota and period to apply from the next period on.
+    pending: Option<(u64, u64)>,
+    period_start: u64,
+    /// Ticks used in the current period.
+    runtime: u64,
+    /// Throttled since the time stored.
+    throttled_since: Option<u64>,
+    /// Ready threads withheld while throttled.
+    throttled: Vec<Tid>,
+    /// Threads in the group, ready or not.
+    members: Vec<Tid>,
+    stats: ThrottleStats,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct BandwidthProcInfo {
+    /// Queued in the wrapped scheduler or withheld.
+    present: bool,
+    group: Option<BandwidthGroupId>,
+}
+
+impl<S: Scheduler, I: Instrument> Scheduler for BandwidthScheduler<S, I> {
+    fn push(&self, tid: usize) {
+        self.lock().push(&self.scheduler, tid);
+        self.instrument.on_push(tid);
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.lock().pop(&self.scheduler, cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let need_reschedule = self.lock().tick(&self.scheduler, current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    fn set_priority(&self, tid: usize, priority: u8) {
+        self.scheduler.set_priority(tid, priority);
+    }
+    fn remove(&self, tid: usize) {
+        self.lock().remove(&self.scheduler, tid);
+    }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.scheduler.set_affinity(tid, mask);
+    }
+}
+
+impl<S: Scheduler> BandwidthScheduler<S> {
+    pub fn new(scheduler: S, clock: &'static dyn Clock) -> Self {
+        Self::with_instrument(scheduler, clock, NoInstrument)
+    }
+}
+
+impl<S: Scheduler, I: Instrument> BandwidthScheduler<S, I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    ///
+    /// Threads are scheduled by `scheduler` while their group is within its quota,
+    /// periods are measured on `clock`.
+    pub fn with_instrument(scheduler: S, clock: &'static dyn Clock, instrument: I) -> Self {
+        let inner = BandwidthSchedulerInner {
+            now: clock.now(),
+            groups: Vec::default(),
+            infos: Vec::default(),
+        };
+        BandwidthScheduler {
+            inner: Mutex::new(inner),
+            scheduler,
+            clock,
+            instrument,
+        }
+    }
+
+    /// Lock the state, with the time read from the clock.
+    fn lock(&self) -> MutexGuard<'_, BandwidthSchedulerInner> {
+        let mut inner = self.inner.lock();
+        inner.now = inner.now.max(self.clock.now());
+        inner
+    }
+
+    /// The wrapped scheduler.
+    pub fn scheduler(&self) -> &S {
+        &self.scheduler
+    }
+
+    /// Create a group allowed to run `quota` ticks every `period` ticks.
+    ///
+    /// The quota may exceed the period for a group running on several CPUs.
+    pub fn new_group(&self, quota: u64, period: u64) -> BandwidthGroupId {
+        assert!(
+            quota > 0 && period > 0,
+            "bandwidth: quota and period must be positive"
+        );
+        let mut inner = self.lock();
+        let group = BandwidthGroup {
+            quota,
+            period,
+            pending: None,
+            period_start: inner.now,
+            runtime: 0,
+            throttled_since: None,
+            throttled: Vec::new(),
+            members: Vec::new(),
+            stats: ThrottleStats::default(),
+        };
+        inner.groups.push(group);
+        BandwidthGroupId(inner.groups.len() - 1)
+    }
+
+    /// Change the quota and period of `group`, from the next period on.
+    pub fn set_bandwidth(&self, group: BandwidthGroupId, quota: u64, period: u64) {
+        assert!(
+            quota > 0 && period > 0,
+            "bandwidth: quota and period must be positive"
+        );
+        self.inner.lock().groups[group.0].pending = Some((quota, period));
+    }
+
+    /// Put a thread in `group`, or in none.
+    pub fn set_group(&self, tid: Tid, group: Option<BandwidthGroupId>) {
+        self.lock().set_group(&self.scheduler, tid, group);
+    }
+
+    /// Whether `group` used up its quota for this period.
+    pub fn is_throttled(&self, group: BandwidthGroupId) -> bool {
+        self.inner.lock().groups[group.0].throttled_since.is_some()
+    }
+
+    /// Throttling statistics of `group`.
+    pub fn stats(&self, group: BandwidthGroupId) -> ThrottleStats {
+        self.inner.lock().groups[group.0].stats
+    }
+}
+
+impl BandwidthSchedulerInner {
+    fn push(&mut self, scheduler: &dyn Scheduler, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if info.present {
+            return;
+        }
+        info.present = true;
+        match info.group.map(|id| &mut self.groups[id.0]) {
+            Some(group) if group.throttled_since.is_some() => {
+                trace!("bandwidth push {} throttled", tid);
+                group.throttled.push(tid);
+            }
+            _ => scheduler.push(tid),
+        }
+    }
+
+    fn pop(&mut self, scheduler: &dyn Scheduler, cpu_id: usize) -> Option<Tid> {
+        self.refill(scheduler);
+        let tid = scheduler.pop(cpu_id)?;
+        expand(&mut self.infos, tid);
+        self.infos[tid].present = false;
+        Some(tid)
+    }
+
+    /// Charge the tick to the group of `current`, throttling it at the end of its quota.
+    fn tick(&mut self, scheduler: &dyn Scheduler, current: Tid) -> bool {
+        self.refill(scheduler);
+        let need_reschedule = scheduler.tick(current);
+
+        expand(&mut self.infos, current);
+        let id = match self.infos[current].group {
+            Some(id) => id,
+            None => return need_reschedule,
+        };
+        let group = &mut self.groups[id.0];
+        group.runtime += 1;
+        if group.throttled_since.is_some() {
+            // it was running when the group was throttled from another CPU
+            return true;
+        }
+        if group.runtime < group.quota {
+            return need_reschedule;
+        }
+        self.throttle(scheduler, id);
+        true
+    }
+
+    /// Withhold the ready threads of `id` until its next period.
+    fn throttle(&mut self, scheduler: &dyn Scheduler, id: BandwidthGroupId) {
+        let infos = &self.infos;
+        let group = &mut self.groups[id.0];
+        let ready = group.members.iter().filter(|&&tid| infos[tid].present);
+        group.throttled.extend(ready);
+        for &tid in group.throttled.iter() {
+            scheduler.remove(tid);
+        }
+        group.throttled_since = Some(self.now);
+        group.stats.nr_throttled += 1;
+        trace!(
+            "bandwidth throttle {:?} until {}",
+            id,
+            group.period_start + group.period
+        );
+    }
+
+    /// Start the new periods of every group, giving back the threads withheld.
+    fn refill(&mut self, scheduler: &dyn Scheduler) {
+        let now = self.now;
+        for (i, group) in self.groups.iter_mut().enumerate() {
+            if now < group.period_start + group.period {
+                continue;
+            }
+            let periods = (now - group.period_start) / group.period;
+            group.period_start += periods * group.period;
+            group.stats.nr_periods += periods;
+            group.runtime = 0;
+            if let Some((quota, period)) = group.pending.take() {
+                group.quota = quota;
+                group.period = period;
+            }
+            if let Some(since) = group.throttled_since.take() {
+                group.stats.throttled_ticks += now - since;
+                trace!("bandwidth unthrottle {:?}", BandwidthGroupId(i));
+                for tid in group.throttled.drain(..) {
+                    scheduler.push(tid);
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, scheduler: &dyn Scheduler, tid: Tid) {
+        let info = match self.infos.get_mut(tid) {
+            Some(info) if info.present => info,
+            _ => return,
+        };
+        info.present = false;
+        match info.group.map(|id| &mut self.groups[id.0]) {
+            Some(group) if group.throttled_since.is_some() => {
+                group.throttled.retain(|&t| t != tid);
+            }
+            _ => scheduler.remove(tid),
+        }
+    }
+
+    fn set_group(&mut self, scheduler: &dyn Scheduler, tid: Tid, group: Option<BandwidthGroupId>) {
+        if let Some(id) = group {
+            assert!(id.0 < self.groups.len(), "bandwidth: no such group");
+        }
+        expand(&mut self.infos, tid);
+        let present = self.infos[tid].present;
+        self.remove(scheduler, tid);
+        if let Some(old) = self.infos[tid].group {
+            self.groups[old.0].members.retain(|&t| t != tid);
+        }
+        if let Some(id) = group {
+            self.groups[id.0].members.push(tid);
+        }
+        self.infos[tid].group = group;
+        if present {
+            self.push(scheduler, tid);
+        }
+        trace!("bandwidth {} group = {:?}", tid, group);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::TestClock;
+    use super::*;
+    use alloc::vec;
+
+    fn bandwidth<S: Scheduler>(scheduler: S) -> (BandwidthScheduler<S>, &'static TestClock) {
+        let clock = TestClock::leak();
+        (BandwidthScheduler::new(scheduler, clock), clock)
+    }
+
+    /// Run `n` always-ready threads on CPU 0, the clock moving one per tick.
+    fn share(s: &dyn Scheduler, clock: &TestClock, n: usize, ticks: u64) -> Vec<u64> {
+        let mut runs = vec![0; n];
+        for tid in 0..n {
+            s.push(tid);
+        }
+        let mut current = s.pop(0).unwrap();
+        for now in 1..=ticks {
+            runs[current] += 1;
+            clock.set(now);
+            if s.tick(current) {
+                s.push(current);
+                current = s.pop(0).unwrap();
+            }
+        }
+        runs
+    }
+
+    #[test]
+    fn quota_caps_the_group() {
+        let (s, clock) = bandwidth(RRScheduler::new(1));
+        let batch = s.new_group(2, 10);
+        s.set_group(1, Some(batch));
+        s.set_group(2, Some(batch));
+        let runs = share(&s, clock, 3, 1000);
+        // 1 and 2 together run 2 ticks in 10
+        assert_eq!(runs[1] + runs[2], 200, "{:?}", runs);
+        assert_eq!(runs[0], 800);
+        let stats = s.stats(batch);
+        assert_eq!(stats.nr_periods, 100);
+        assert_eq!(stats.nr_throttled, 100);
+    }
+
+    #[test]
+    fn throttled_group_is_refilled_without_a_tick() {
+        let (s, clock) = bandwidth(RRScheduler::new(5));
+        let g = s.new_group(1, 100);
+        s.set_group(0, Some(g));
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        clock.set(1);
+        assert!(s.tick(0));
+        assert!(s.is_throttled(g));
+        s.push(0);
+        assert_eq!(s.pop(0), None);
+        clock.set(99);
+        assert_eq!(s.pop(0), None);
+        // every CPU idles, the next pop refills it
+        clock.set(100);
+        assert_eq!(s.pop(0), Some(0));
+        assert!(!s.is_throttled(g));
+        let stats = ThrottleStats {
+            nr_periods: 1,
+            nr_throttled: 1,
+            throttled_ticks: 99,
+        };
+        assert_eq!(s.stats(g), stats);
+    }
+
+    #[test]
+    fn new_bandwidth_applies_next_period() {
+        let (s, clock) = bandwidth(RRScheduler::new(100));
+        let g = s.new_group(2, 10);
+        s.set_group(0, Some(g));
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        s.set_bandwidth(g, 5, 10);
+        // still the old quota in this period
+        clock.set(1);
+        assert!(!s.tick(0));
+        clock.set(2);
+        assert!(s.tick(0));
+        s.push(0);
+        assert_eq!(s.pop(0), None);
+        clock.set(10);
+        assert_eq!(s.pop(0), Some(0));
+        for now in 11..15 {
+            clock.set(now);
+            assert!(!s.tick(0));
+        }
+        clock.set(15);
+        assert!(s.tick(0));
+        assert!(s.is_throttled(g));
+    }
+
+    #[test]
+    fn ungrouped_threads_are_never_throttled() {
+        let (s, clock) = bandwidth(RRScheduler::new(1));
+        let g = s.new_group(1, 10);
+        s.set_group(1, Some(g));
+        let runs = share(&s, clock, 1, 100);
+        assert_eq!(runs, vec![100]);
+        assert_eq!(s.stats(g).nr_throttled, 0);
+        assert_eq!(s.stats(g).nr_periods, 10);
+    }
+}
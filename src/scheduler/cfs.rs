@@ -0,0 +1,286 @@
+//! Completely Fair Scheduler
+//!
+//! Each task accumulates a virtual runtime, the time it ran weighted by its nice level.
+//! The task with the least virtual runtime is selected to run, from a tree ordered by it.
+//! A task runs for its share of the target latency, at least the minimum granularity.
+
+use super::*;
+use alloc::collections::BTreeSet;
+
+pub struct CfsScheduler<I: Instrument = NoInstrument> {
+    inner: Mutex<CfsSchedulerInner>,
+    instrument: I,
+}
+
+struct CfsSchedulerInner {
+    min_granularity: usize,
+    target_latency: usize,
+    infos: Vec<CfsProcInfo>,
+    /// Ready tasks ordered by virtual runtime.
+    tree: BTreeSet<(u64, Tid)>,
+    /// Total weight of the ready tasks.
+    load: u64,
+    /// Monotonic lower bound of the virtual runtimes, new and waking tasks start from it.
+    min_vruntime: u64,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct CfsProcInfo {
+    present: bool,
+    /// Has been pushed at least once.
+    started: bool,
+    vruntime: u64,
+    nice: i8,
+    /// Ticks run since the task was last picked.
+    slice_runtime: usize,
+    affinity: CpuMask,
+}
+
+/// Weight of a nice 0 task. One tick at nice 0 adds this much virtual runtime.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weights of nice -20 to 19, each level is about 10% of CPU time apart.
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+impl CfsProcInfo {
+    fn weight(&self) -> u64 {
+        NICE_TO_WEIGHT[(self.nice as i32 + 20) as usize]
+    }
+
+    /// Virtual runtime of one tick.
+    fn tick_vruntime(&self) -> u64 {
+        NICE_0_WEIGHT * NICE_0_WEIGHT / self.weight()
+    }
+}
+
+impl<I: Instrument> Scheduler for CfsScheduler<I> {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+        self.instrument.on_push(tid);
+        platform::wake_idle_cpu();
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.inner.lock().pop(cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let need_reschedule = self.inner.lock().tick(current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    /// Priorities 0 to 39 map to nice 19 to -20, so 19 is nice 0.
+    fn set_priority(&self, tid: usize, priority: u8) {
+        let nice = 19 - priority.min(39) as i8;
+        self.inner.lock().set_nice(tid, nice);
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.inner.lock().set_affinity(tid, mask);
+    }
+}
+
+impl CfsScheduler {
+    pub fn new(min_granularity: usize, target_latency: usize) -> Self {
+        Self::with_instrument(min_granularity, target_latency, NoInstrument)
+    }
+}
+
+impl<I: Instrument> CfsScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    ///
+    /// Every ready task runs once within `target_latency` ticks,
+    /// unless that leaves less than `min_granularity` ticks to each.
+    pub fn with_instrument(min_granularity: usize, target_latency: usize, instrument: I) -> Self {
+        assert!(min_granularity > 0, "cfs: min_granularity must be positive");
+        let inner = CfsSchedulerInner {
+            min_granularity,
+            target_latency: target_latency.max(min_granularity),
+            infos: Vec::default(),
+            tree: BTreeSet::default(),
+            load: 0,
+            min_vruntime: 0,
+        };
+        CfsScheduler {
+            inner: Mutex::new(inner),
+            instrument,
+        }
+    }
+
+    /// Set the nice level of a thread, clamped to -20..=19.
+    pub fn set_nice(&self, tid: Tid, nice: i8) {
+        self.inner.lock().set_nice(tid, nice);
+    }
+}
+
+impl CfsSchedulerInner {
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let sleeper_credit = self.target_latency as u64 * NICE_0_WEIGHT / 2;
+        let min_vruntime = self.min_vruntime;
+        let info = &mut self.infos[tid];
+        if info.present {
+            return;
+        }
+        if !info.started {
+            // new tasks start at the back
+            info.started = true;
+            info.vruntime = min_vruntime;
+        } else {
+            // a waking task gets up to half a latency of credit, but no more,
+            // however long it slept
+            info.vruntime = info
+                .vruntime
+                .max(min_vruntime.saturating_sub(sleeper_credit));
+        }
+        info.present = true;
+        self.load += info.weight();
+        self.tree.insert((info.vruntime, tid));
+        trace!("cfs push {} vruntime {}", tid, info.vruntime);
+    }
+
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        let infos = &self.infos;
+        let &(vruntime, tid) = self
+            .tree
+            .iter()
+            .find(|&&(_, tid)| infos[tid].affinity.contains(cpu_id))?;
+        self.tree.remove(&(vruntime, tid));
+        let info = &mut self.infos[tid];
+        info.present = false;
+        info.slice_runtime = 0;
+        self.load -= info.weight();
+        // tasks skipped for their affinity may be behind the one popped
+        let leftmost = self.tree.iter().next().map(|&(v, _)| v);
+        let min = leftmost.map_or(vruntime, |v| v.min(vruntime));
+        self.min_vruntime = self.min_vruntime.max(min);
+        trace!("cfs pop {} vruntime {}", tid, vruntime);
+        Some(tid)
+    }
+
+    /// The ticks `info` should run for, given the tasks waiting.
+    fn ideal_slice(&self, info: &CfsProcInfo) -> usize {
+        let weight = info.weight();
+        let load = self.load + weight;
+        let nr_running = self.tree.len() + 1;
+        let period = self.target_latency.max(nr_running * self.min_granularity) as u64;
+        let slice = (period * weight / load) as usize;
+        slice.max(self.min_granularity)
+    }
+
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        let info = &mut self.infos[current];
+        info.vruntime += info.tick_vruntime();
+        info.slice_runtime += 1;
+        let info = self.infos[current];
+
+        let leftmost = self.tree.iter().next().map(|&(vruntime, _)| vruntime);
+        let min = leftmost.map_or(info.vruntime, |v| v.min(info.vruntime));
+        self.min_vruntime = self.min_vruntime.max(min);
+
+        info.slice_runtime >= self.ideal_slice(&info)
+    }
+
+    fn set_nice(&mut self, tid: Tid, nice: i8) {
+        expand(&mut self.infos, tid);
+        let nice = nice.clamp(-20, 19);
+        let info = &mut self.infos[tid];
+        if info.present {
+            self.load = self.load - info.weight() + NICE_TO_WEIGHT[(nice + 20) as usize];
+        }
+        info.nice = nice;
+        trace!("cfs {} nice = {}", tid, nice);
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        if let Some(info) = self.infos.get_mut(tid) {
+            if info.present {
+                info.present = false;
+                self.load -= info.weight();
+                self.tree.remove(&(info.vruntime, tid));
+            }
+        }
+    }
+
+    fn set_affinity(&mut self, tid: Tid, mask: CpuMask) {
+        expand(&mut self.infos, tid);
+        self.infos[tid].affinity = mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{about, share};
+    use super::*;
+
+    #[test]
+    fn ticks_follow_weights() {
+        let s = CfsScheduler::new(1, 6);
+        s.set_nice(0, -5);
+        let runs = share(&s, 3, 6000);
+        let weight = |nice: i8| NICE_TO_WEIGHT[(nice + 20) as usize] as f64;
+        assert!(about(runs[0], runs[1], weight(-5) / weight(0), 0.05));
+        assert!(about(runs[1], runs[2], 1.0, 0.05));
+    }
+
+    #[test]
+    fn sleeper_credit_is_bounded() {
+        let s = CfsScheduler::new(1, 20);
+        s.push(0);
+        s.push(1);
+        assert_eq!(s.pop(0), Some(0));
+        assert_eq!(s.pop(0), Some(1));
+        // 1 sleeps while 0 runs alone for 100 ticks
+        for _ in 0..100 {
+            s.tick(0);
+        }
+        s.push(0);
+        s.push(1);
+        s.push(2);
+        let inner = s.inner.lock();
+        assert_eq!(inner.min_vruntime, 100 * NICE_0_WEIGHT);
+        // half a target latency of credit
+        assert_eq!(inner.infos[1].vruntime, 90 * NICE_0_WEIGHT);
+        // a new task starts at the back
+        assert_eq!(inner.infos[2].vruntime, 100 * NICE_0_WEIGHT);
+    }
+
+    #[test]
+    fn nice_is_clamped() {
+        let s = CfsScheduler::new(1, 6);
+        s.set_nice(0, -100);
+        s.set_nice(1, 100);
+        s.set_priority(2, 200);
+        s.set_priority(3, 0);
+        let inner = s.inner.lock();
+        let nice: Vec<i8> = inner.infos.iter().map(|info| info.nice).collect();
+        assert_eq!(nice, [-20, 19, -20, 19]);
+    }
+
+    #[test]
+    fn min_vruntime_stays_below_skipped_tasks() {
+        let s = CfsScheduler::new(1, 6);
+        s.set_affinity(0, CpuMask::single(1));
+        s.push(0);
+        s.push(1);
+        assert_eq!(s.pop(0), Some(1));
+        for _ in 0..5 {
+            s.tick(1);
+        }
+        s.push(1);
+        // 0 is skipped, still at vruntime 0
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.inner.lock().min_vruntime, 0);
+        assert_eq!(s.pop(1), Some(0));
+    }
+}
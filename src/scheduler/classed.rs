@@ -0,0 +1,394 @@
+//! Scheduling classes
+//!
+//! Threads are split into classes by their [`Policy`], consulted in order:
+//! real-time FIFO and round robin threads first, by priority,
+//! then normal threads through any fair scheduler, then idle threads.
+//! A thread of a higher class becoming ready preempts the lower ones on the next tick.
+//! A real-time thread preempted by a higher priority one goes back to the head of its queue.
+
+use super::*;
+use alloc::collections::{BTreeMap, VecDeque};
+
+/// The scheduling class of a thread.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Policy {
+    /// Real-time, runs until it blocks or a higher priority thread is ready.
+    Fifo,
+    /// Real-time, like `Fifo` but shares its priority level in time slices.
+    RoundRobin,
+    /// Handled by the fair scheduler.
+    #[default]
+    Normal,
+    /// Runs only when nothing else is ready.
+    Idle,
+}
+
+pub struct ClassedScheduler<F: Scheduler, I: Instrument = NoInstrument> {
+    inner: Mutex<ClassedSchedulerInner>,
+    fair: F,
+    instrument: I,
+}
+
+struct ClassedSchedulerInner {
+    rr_time_slice: usize,
+    infos: Vec<ClassedProcInfo>,
+    /// Ready real-time threads by priority, higher first.
+    rt_queues: BTreeMap<u8, VecDeque<Tid>>,
+    /// Number of ready normal threads, queued in the fair scheduler.
+    nr_fair: usize,
+    idle_queue: VecDeque<Tid>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct ClassedProcInfo {
+    present: bool,
+    policy: Policy,
+    priority: u8,
+    rest_slice: usize,
+    /// Preempted by a higher priority thread, goes back to the head of its queue.
+    preempted: bool,
+    affinity: CpuMask,
+}
+
+impl<F: Scheduler, I: Instrument> Scheduler for ClassedScheduler<F, I> {
+    fn push(&self, tid: usize) {
+        let fair = self.inner.lock().push(tid);
+        if fair {
+            self.fair.push(tid);
+        } else {
+            platform::wake_idle_cpu();
+        }
+        self.instrument.on_push(tid);
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.inner.lock().pop(&self.fair, cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let cpu = platform::cpu_id();
+        let need_reschedule = self.inner.lock().tick(&self.fair, cpu, current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    /// Set the real-time priority of a thread, and its priority in the fair scheduler.
+    fn set_priority(&self, tid: usize, priority: u8) {
+        self.inner.lock().set_priority(tid, priority);
+        self.fair.set_priority(tid, priority);
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(&self.fair, tid);
+    }
+    /// Set the affinity of a thread, in the fair scheduler too.
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.inner.lock().set_affinity(tid, mask);
+        self.fair.set_affinity(tid, mask);
+    }
+}
+
+impl<F: Scheduler> ClassedScheduler<F> {
+    pub fn new(fair: F, rr_time_slice: usize) -> Self {
+        Self::with_instrument(fair, rr_time_slice, NoInstrument)
+    }
+}
+
+impl<F: Scheduler, I: Instrument> ClassedScheduler<F, I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    ///
+    /// Normal threads are scheduled by `fair`,
+    /// round robin threads run `rr_time_slice` ticks in turn.
+    pub fn with_instrument(fair: F, rr_time_slice: usize, instrument: I) -> Self {
+        let inner = ClassedSchedulerInner {
+            rr_time_slice,
+            infos: Vec::default(),
+            rt_queues: BTreeMap::default(),
+            nr_fair: 0,
+            idle_queue: VecDeque::default(),
+        };
+        ClassedScheduler {
+            inner: Mutex::new(inner),
+            fair,
+            instrument,
+        }
+    }
+
+    /// The scheduler of normal threads.
+    pub fn fair(&self) -> &F {
+        &self.fair
+    }
+
+    /// Move a thread to the class of `policy`.
+    pub fn set_policy(&self, tid: Tid, policy: Policy) {
+        let mut inner = self.inner.lock();
+        expand(&mut inner.infos, tid);
+        let present = inner.infos[tid].present;
+        inner.remove(&self.fair, tid);
+        inner.infos[tid].policy = policy;
+        inner.infos[tid].rest_slice = 0;
+        inner.infos[tid].preempted = false;
+        trace!("classed {} policy = {:?}", tid, policy);
+        if present && inner.push(tid) {
+            self.fair.push(tid);
+        }
+    }
+}
+
+impl ClassedSchedulerInner {
+    /// Return true if the thread goes to the fair scheduler.
+    fn push(&mut self, tid: Tid) -> bool {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if info.present {
+            return false;
+        }
+        info.present = true;
+        trace!("classed push {} {:?}", tid, info.policy);
+        match info.policy {
+            Policy::Fifo | Policy::RoundRobin => {
+                if info.rest_slice == 0 {
+                    info.rest_slice = self.rr_time_slice;
+                }
+                let queue = self.rt_queues.entry(info.priority).or_default();
+                if core::mem::take(&mut info.preempted) {
+                    queue.push_front(tid);
+                } else {
+                    queue.push_back(tid);
+                }
+                false
+            }
+            Policy::Normal => {
+                self.nr_fair += 1;
+                true
+            }
+            Policy::Idle => {
+                self.idle_queue.push_back(tid);
+                false
+            }
+        }
+    }
+
+    fn pop(&mut self, fair: &dyn Scheduler, cpu_id: usize) -> Option<Tid> {
+        let tid = match self.pop_rt(cpu_id) {
+            Some(tid) => tid,
+            None => match fair.pop(cpu_id) {
+                Some(tid) => {
+                    self.nr_fair -= 1;
+                    tid
+                }
+                None => {
+                    let infos = &self.infos;
+                    let i = self
+                        .idle_queue
+                        .iter()
+                        .position(|&tid| infos[tid].affinity.contains(cpu_id))?;
+                    self.idle_queue.remove(i).unwrap()
+                }
+            },
+        };
+        self.infos[tid].present = false;
+        trace!("classed pop {}", tid);
+        Some(tid)
+    }
+
+    /// Pop the first real-time thread of the highest priority allowed on `cpu_id`.
+    fn pop_rt(&mut self, cpu_id: usize) -> Option<Tid> {
+        let infos = &self.infos;
+        let (priority, tid) = self.rt_queues.iter().rev().find_map(|(&priority, queue)| {
+            let tid = queue
+                .iter()
+                .cloned()
+                .find(|&tid| infos[tid].affinity.contains(cpu_id));
+            tid.map(|tid| (priority, tid))
+        })?;
+        self.remove_rt(tid, priority);
+        Some(tid)
+    }
+
+    /// Highest priority of the ready real-time threads allowed on `cpu_id`.
+    fn top_rt_priority(&self, cpu_id: usize) -> Option<u8> {
+        let infos = &self.infos;
+        self.rt_queues
+            .iter()
+            .rev()
+            .find(|(_, queue)| {
+                queue
+                    .iter()
+                    .any(|&tid| infos[tid].affinity.contains(cpu_id))
+            })
+            .map(|(&priority, _)| priority)
+    }
+
+    fn tick(&mut self, fair: &dyn Scheduler, cpu_id: usize, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        let top_rt = self.top_rt_priority(cpu_id);
+        let info = &mut self.infos[current];
+        match info.policy {
+            Policy::Fifo | Policy::RoundRobin => {
+                if info.policy == Policy::RoundRobin {
+                    info.rest_slice = info.rest_slice.saturating_sub(1);
+                    if info.rest_slice == 0 {
+                        return true;
+                    }
+                }
+                info.preempted = matches!(top_rt, Some(top) if top > info.priority);
+                info.preempted
+            }
+            // let the fair scheduler account the tick even when preempted
+            Policy::Normal => fair.tick(current) || top_rt.is_some(),
+            Policy::Idle => top_rt.is_some() || self.nr_fair != 0 || !self.idle_queue.is_empty(),
+        }
+    }
+
+    fn set_priority(&mut self, tid: Tid, priority: u8) {
+        expand(&mut self.infos, tid);
+        let info = self.infos[tid];
+        if info.present && (info.policy == Policy::Fifo || info.policy == Policy::RoundRobin) {
+            self.remove_rt(tid, info.priority);
+            self.rt_queues.entry(priority).or_default().push_back(tid);
+        }
+        self.infos[tid].priority = priority;
+    }
+
+    fn remove_rt(&mut self, tid: Tid, priority: u8) {
+        if let Some(queue) = self.rt_queues.get_mut(&priority) {
+            queue.retain(|&t| t != tid);
+            if queue.is_empty() {
+                self.rt_queues.remove(&priority);
+            }
+        }
+    }
+
+    fn remove(&mut self, fair: &dyn Scheduler, tid: Tid) {
+        let info = match self.infos.get_mut(tid) {
+            Some(info) if info.present => info,
+            _ => return,
+        };
+        info.present = false;
+        info.preempted = false;
+        let info = *info;
+        match info.policy {
+            Policy::Fifo | Policy::RoundRobin => self.remove_rt(tid, info.priority),
+            Policy::Normal => {
+                self.nr_fair -= 1;
+                fair.remove(tid);
+            }
+            Policy::Idle => self.idle_queue.retain(|&t| t != tid),
+        }
+    }
+
+    fn set_affinity(&mut self, tid: Tid, mask: CpuMask) {
+        expand(&mut self.infos, tid);
+        self.infos[tid].affinity = mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classed() -> ClassedScheduler<CfsScheduler> {
+        ClassedScheduler::new(CfsScheduler::new(1, 6), 2)
+    }
+
+    #[test]
+    fn classes_in_order() {
+        let s = classed();
+        s.set_policy(0, Policy::Idle);
+        s.set_policy(2, Policy::RoundRobin);
+        s.set_policy(3, Policy::Fifo);
+        s.set_priority(3, 5);
+        for tid in 0..4 {
+            s.push(tid);
+        }
+        assert_eq!(s.pop(0), Some(3));
+        assert_eq!(s.pop(0), Some(2));
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), Some(0));
+        assert_eq!(s.pop(0), None);
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let s = classed();
+        s.set_policy(0, Policy::RoundRobin);
+        s.set_policy(1, Policy::RoundRobin);
+        s.push(0);
+        s.push(1);
+        let mut current = s.pop(0).unwrap();
+        let mut runs = Vec::new();
+        for _ in 0..8 {
+            runs.push(current);
+            if s.tick(current) {
+                s.push(current);
+                current = s.pop(0).unwrap();
+            }
+        }
+        assert_eq!(runs, [0, 0, 1, 1, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn set_policy_requeues_the_thread() {
+        let s = classed();
+        s.push(0);
+        s.push(1);
+        s.set_policy(1, Policy::Fifo);
+        s.set_policy(0, Policy::Idle);
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), Some(0));
+        assert_eq!(s.pop(0), None);
+        // back to normal, it is queued in the fair scheduler again
+        s.push(1);
+        s.set_policy(1, Policy::Normal);
+        assert_eq!(s.fair().pop(0), Some(1));
+    }
+
+    #[test]
+    fn anything_ready_preempts_idle() {
+        let s = classed();
+        s.set_policy(0, Policy::Idle);
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        assert!(!s.tick(0));
+        s.push(1);
+        assert!(s.tick(0));
+    }
+
+    #[test]
+    fn preempted_fifo_thread_keeps_its_place() {
+        let s = classed();
+        for tid in 0..3 {
+            s.set_policy(tid, Policy::Fifo);
+            s.set_priority(tid, 1);
+        }
+        s.set_priority(2, 5);
+        s.push(0);
+        s.push(1);
+        assert_eq!(s.pop(0), Some(0));
+        assert!(!s.tick(0));
+        s.push(2);
+        assert!(s.tick(0));
+        s.push(0);
+        assert_eq!(s.pop(0), Some(2));
+        // back at the head, ahead of 1
+        assert_eq!(s.pop(0), Some(0));
+        assert_eq!(s.pop(0), Some(1));
+    }
+
+    #[test]
+    fn only_threads_allowed_here_preempt() {
+        let s = classed();
+        s.set_policy(0, Policy::Fifo);
+        s.set_policy(1, Policy::Fifo);
+        s.set_priority(1, 5);
+        s.set_affinity(1, CpuMask::single(1));
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        s.push(1);
+        // ticks come from CPU 0, where 1 may not run
+        assert!(!s.tick(0));
+        assert_eq!(s.pop(1), Some(1));
+    }
+}
@@ -0,0 +1,394 @@
+//! Earliest Deadline First scheduler
+//!
+//! Real-time threads are admitted with their [`RtParams`] as long as the total density,
+//! runtime over deadline, stays within the bound. The ready thread with the earliest
+//! absolute deadline is selected to run.
+//!
+//! Each thread is served by a Constant Bandwidth Server: once it used up its runtime,
+//! it is throttled until its deadline, then its budget is refilled and its deadline
+//! postponed by a period. A thread overrunning its parameters can not hurt the others.
+//!
+//! Threads never admitted run in FIFO order when no real-time thread is ready.
+//! Runtimes, deadlines and periods are in ticks of the clock given at construction,
+//! budgets are charged with the time a thread ran and refilled on `pop` too,
+//! so throttled threads come back even when every CPU went idle.
+
+use super::*;
+use crate::asynchronous::stats::Clock;
+use alloc::collections::{BTreeSet, VecDeque};
+use spin::MutexGuard;
+
+/// Fixed point shift of bandwidths.
+const BW_SHIFT: u32 = 20;
+
+fn bandwidth(runtime: u64, period: u64) -> u64 {
+    (runtime << BW_SHIFT) / period
+}
+
+/// Why a thread was not admitted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AdmissionError {
+    /// The runtime is zero or longer than the deadline, or the deadline exceeds the period.
+    InvalidParams,
+    /// The thread would push the total density over the bound.
+    Overloaded,
+}
+
+pub struct EdfScheduler<I: Instrument = NoInstrument> {
+    inner: Mutex<EdfSchedulerInner>,
+    clock: &'static dyn Clock,
+    instrument: I,
+}
+
+struct EdfSchedulerInner {
+    /// Clock time when the lock was last taken.
+    now: u64,
+    max_bandwidth: u64,
+    total_bandwidth: u64,
+    infos: Vec<EdfProcInfo>,
+    /// Ready and not throttled real-time threads, by absolute deadline.
+    ready: BTreeSet<(u64, Tid)>,
+    background: VecDeque<Tid>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct EdfProcInfo {
+    present: bool,
+    params: Option<RtParams>,
+    /// Absolute deadline of the current job.
+    deadline: u64,
+    /// Runtime left until the deadline.
+    budget: u64,
+    /// Out of budget, not eligible until the time stored.
+    throttled_until: Option<u64>,
+    /// When the budget was last charged, while running.
+    charged_at: u64,
+    affinity: CpuMask,
+}
+
+impl<I: Instrument> Scheduler for EdfScheduler<I> {
+    fn push(&self, tid: usize) {
+        self.lock().push(tid);
+        self.instrument.on_push(tid);
+        platform::wake_idle_cpu();
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.lock().pop(cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let need_reschedule = self.lock().tick(current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    /// Deadlines decide, priorities are ignored.
+    fn set_priority(&self, _tid: usize, _priority: u8) {}
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.inner.lock().set_affinity(tid, mask);
+    }
+}
+
+impl EdfScheduler {
+    pub fn new(max_runtime: u64, max_period: u64, clock: &'static dyn Clock) -> Self {
+        Self::with_instrument(max_runtime, max_period, clock, NoInstrument)
+    }
+}
+
+impl<I: Instrument> EdfScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    ///
+    /// Real-time threads may use at most `max_runtime` ticks of `clock`
+    /// every `max_period` ticks together.
+    pub fn with_instrument(
+        max_runtime: u64,
+        max_period: u64,
+        clock: &'static dyn Clock,
+        instrument: I,
+    ) -> Self {
+        assert!(max_runtime <= max_period, "edf: bound over 100%");
+        let inner = EdfSchedulerInner {
+            now: clock.now(),
+            max_bandwidth: bandwidth(max_runtime, max_period),
+            total_bandwidth: 0,
+            infos: Vec::default(),
+            ready: BTreeSet::default(),
+            background: VecDeque::default(),
+        };
+        EdfScheduler {
+            inner: Mutex::new(inner),
+            clock,
+            instrument,
+        }
+    }
+
+    /// Lock the state, with the time read from the clock.
+    fn lock(&self) -> MutexGuard<'_, EdfSchedulerInner> {
+        let mut inner = self.inner.lock();
+        inner.now = inner.now.max(self.clock.now());
+        inner
+    }
+
+    /// Make `tid` a real-time thread with `params`, if they fit.
+    ///
+    /// The parameters of a thread already admitted are replaced.
+    pub fn admit(&self, tid: Tid, params: RtParams) -> Result<(), AdmissionError> {
+        self.lock().admit(tid, params)
+    }
+
+    /// Make `tid` a background thread again, releasing its bandwidth.
+    pub fn leave(&self, tid: Tid) {
+        self.lock().leave(tid);
+    }
+
+    /// Total density of the admitted threads, in parts per million.
+    pub fn utilization(&self) -> u64 {
+        (self.inner.lock().total_bandwidth * 1_000_000) >> BW_SHIFT
+    }
+}
+
+impl EdfSchedulerInner {
+    fn admit(&mut self, tid: Tid, params: RtParams) -> Result<(), AdmissionError> {
+        if !params.is_valid() {
+            return Err(AdmissionError::InvalidParams);
+        }
+        expand(&mut self.infos, tid);
+        let old = self.infos[tid]
+            .params
+            .map_or(0, |p| bandwidth(p.runtime, p.deadline));
+        let new = bandwidth(params.runtime, params.deadline);
+        if self.total_bandwidth - old + new > self.max_bandwidth {
+            return Err(AdmissionError::Overloaded);
+        }
+        let present = self.infos[tid].present;
+        self.remove(tid);
+        self.total_bandwidth = self.total_bandwidth - old + new;
+        let info = &mut self.infos[tid];
+        info.params = Some(params);
+        info.deadline = self.now + params.deadline;
+        info.budget = params.runtime;
+        info.throttled_until = None;
+        info.charged_at = self.now;
+        if present {
+            self.push(tid);
+        }
+        trace!("edf admit {} {:?}", tid, params);
+        Ok(())
+    }
+
+    fn leave(&mut self, tid: Tid) {
+        let params = match self.infos.get(tid).and_then(|info| info.params) {
+            Some(params) => params,
+            None => return,
+        };
+        let present = self.infos[tid].present;
+        self.remove(tid);
+        self.total_bandwidth -= bandwidth(params.runtime, params.deadline);
+        let info = &mut self.infos[tid];
+        info.params = None;
+        info.throttled_until = None;
+        if present {
+            self.push(tid);
+        }
+    }
+
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let now = self.now;
+        let info = &mut self.infos[tid];
+        if info.present {
+            return;
+        }
+        info.present = true;
+        let params = match info.params {
+            Some(params) => params,
+            None => {
+                self.background.push_back(tid);
+                trace!("edf push {} to background", tid);
+                return;
+            }
+        };
+        if info.throttled_until.is_some() {
+            trace!("edf push {} throttled", tid);
+            return;
+        }
+        // CBS wakeup rule: start a new job if the budget left can not be used
+        // by the deadline without exceeding the reserved bandwidth
+        let left = info.deadline.saturating_sub(now);
+        if left == 0 || info.budget * params.period > left * params.runtime {
+            info.deadline = now + params.deadline;
+            info.budget = params.runtime;
+        }
+        self.ready.insert((info.deadline, tid));
+        trace!("edf push {} deadline {}", tid, info.deadline);
+    }
+
+    /// Refill the threads whose throttling ended.
+    fn replenish(&mut self) {
+        let now = self.now;
+        for tid in 0..self.infos.len() {
+            let info = &mut self.infos[tid];
+            match (info.throttled_until, info.params) {
+                (Some(until), Some(params)) if until <= now => {
+                    info.throttled_until = None;
+                    info.deadline += params.period;
+                    info.budget = params.runtime;
+                    if info.present {
+                        self.ready.insert((info.deadline, tid));
+                    }
+                    trace!("edf replenish {} deadline {}", tid, info.deadline);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        self.replenish();
+        let infos = &self.infos;
+        let allowed = |&tid: &Tid| infos[tid].affinity.contains(cpu_id);
+        let tid = match self.ready.iter().find(|&(_, tid)| allowed(tid)) {
+            Some(&key) => {
+                self.ready.remove(&key);
+                key.1
+            }
+            None => {
+                let i = self.background.iter().position(allowed)?;
+                self.background.remove(i).unwrap()
+            }
+        };
+        let info = &mut self.infos[tid];
+        info.present = false;
+        info.charged_at = self.now;
+        trace!("edf pop {}", tid);
+        Some(tid)
+    }
+
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        self.replenish();
+        let now = self.now;
+        let info = &mut self.infos[current];
+        if info.params.is_none() {
+            // background threads give way to anyone ready
+            return !self.ready.is_empty() || !self.background.is_empty();
+        }
+        let ran = now.saturating_sub(info.charged_at);
+        info.charged_at = now;
+        info.budget = info.budget.saturating_sub(ran);
+        if info.budget == 0 {
+            // hard CBS: throttle until the deadline
+            info.throttled_until = Some(info.deadline.max(self.now));
+            trace!("edf throttle {} until {}", current, info.deadline);
+            return true;
+        }
+        let deadline = info.deadline;
+        matches!(self.ready.iter().next(), Some(&(earliest, _)) if earliest < deadline)
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        if let Some(info) = self.infos.get_mut(tid) {
+            if info.present {
+                info.present = false;
+                self.ready.remove(&(info.deadline, tid));
+                self.background.retain(|&t| t != tid);
+            }
+        }
+    }
+
+    fn set_affinity(&mut self, tid: Tid, mask: CpuMask) {
+        expand(&mut self.infos, tid);
+        self.infos[tid].affinity = mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::TestClock;
+    use super::*;
+
+    fn edf(max_runtime: u64, max_period: u64) -> (EdfScheduler, &'static TestClock) {
+        let clock = TestClock::leak();
+        (EdfScheduler::new(max_runtime, max_period, clock), clock)
+    }
+
+    #[test]
+    fn admission_keeps_density_under_the_bound() {
+        let (s, _) = edf(95, 100);
+        assert_eq!(s.admit(0, RtParams::new(2, 10)), Ok(()));
+        assert_eq!(s.admit(1, RtParams::new(3, 5)), Ok(()));
+        assert_eq!(
+            s.admit(2, RtParams::new(2, 10)),
+            Err(AdmissionError::Overloaded)
+        );
+        assert_eq!(
+            s.admit(2, RtParams::new(0, 10)),
+            Err(AdmissionError::InvalidParams)
+        );
+        assert_eq!(
+            s.admit(2, RtParams::new(5, 4)),
+            Err(AdmissionError::InvalidParams)
+        );
+        assert_eq!(
+            s.admit(2, RtParams::new(1, 10).with_deadline(20)),
+            Err(AdmissionError::InvalidParams)
+        );
+        // new parameters replace the old ones, making room
+        assert_eq!(s.admit(1, RtParams::new(1, 5)), Ok(()));
+        assert_eq!(s.admit(2, RtParams::new(2, 10)), Ok(()));
+        assert!((599_000..=600_000).contains(&s.utilization()));
+        s.leave(2);
+        assert!((399_000..=400_000).contains(&s.utilization()));
+    }
+
+    #[test]
+    fn earliest_deadline_first() {
+        let (s, _) = edf(1, 1);
+        s.admit(0, RtParams::new(1, 30)).unwrap();
+        s.admit(1, RtParams::new(1, 10)).unwrap();
+        s.admit(2, RtParams::new(1, 40).with_deadline(20)).unwrap();
+        s.push(3);
+        for tid in 0..3 {
+            s.push(tid);
+        }
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), Some(2));
+        assert_eq!(s.pop(0), Some(0));
+        // background threads only when no real-time one is ready
+        assert_eq!(s.pop(0), Some(3));
+        assert_eq!(s.pop(0), None);
+    }
+
+    #[test]
+    fn throttled_thread_is_refilled_without_a_tick() {
+        let (s, clock) = edf(1, 1);
+        s.admit(0, RtParams::new(2, 10)).unwrap();
+        s.push(0);
+        s.push(1);
+        assert_eq!(s.pop(0), Some(0));
+        // budget is charged with clock time, not per tick
+        assert!(!s.tick(0));
+        clock.set(1);
+        assert!(!s.tick(0));
+        clock.set(2);
+        assert!(s.tick(0));
+        s.push(0);
+        // throttled until its deadline, the background thread runs
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), None);
+        clock.set(9);
+        assert_eq!(s.pop(0), None);
+        // every CPU idles, the next pop refills it
+        clock.set(10);
+        assert_eq!(s.pop(0), Some(0));
+        let inner = s.inner.lock();
+        assert_eq!(inner.infos[0].deadline, 20);
+        assert_eq!(inner.infos[0].budget, 2);
+    }
+}
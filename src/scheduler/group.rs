@@ -0,0 +1,548 @@
+//! Hierarchical group scheduler
+//!
+//! Threads belong to groups forming a tree, each group dividing the CPU time it gets
+//! between its child groups in proportion to their weights, like cgroup CPU shares.
+//! The threads of a group are scheduled among themselves by the group's own scheduler,
+//! and compete with the child groups as one more child of the default weight.
+//!
+//! At each level, the ready entity with the least virtual runtime is selected,
+//! until a group's own scheduler picks the thread to run.
+
+use super::*;
+use alloc::{boxed::Box, collections::BTreeSet, vec};
+use core::ops::Bound::{Excluded, Unbounded};
+
+/// Weight of the threads of a group against its child groups, and of a new group by default.
+const DEFAULT_WEIGHT: u64 = 1024;
+
+/// A group of threads.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct GroupId(usize);
+
+impl GroupId {
+    /// The group at the root of the tree, which every thread starts in.
+    pub const ROOT: GroupId = GroupId(0);
+}
+
+impl Default for GroupId {
+    fn default() -> Self {
+        GroupId::ROOT
+    }
+}
+
+pub struct GroupScheduler<I: Instrument = NoInstrument> {
+    inner: Mutex<GroupSchedulerInner>,
+    instrument: I,
+}
+
+struct GroupSchedulerInner {
+    min_granularity: u64,
+    /// Groups by id, `None` once destroyed.
+    groups: Vec<Option<Group>>,
+    infos: Vec<GroupProcInfo>,
+}
+
+struct Group {
+    parent: Option<GroupId>,
+    children: Vec<GroupId>,
+    /// Child groups with ready threads, by virtual runtime.
+    ready: BTreeSet<(u64, GroupId)>,
+    weight: u64,
+    /// Virtual runtime of the group among its siblings.
+    vruntime: u64,
+    /// Virtual runtime of the threads of the group among its children.
+    own_vruntime: u64,
+    /// Monotonic lower bound of the virtual runtimes of the entities of the group.
+    min_vruntime: u64,
+    /// Ready threads of the group itself.
+    nr_own: usize,
+    /// Ready threads in the subtree.
+    nr_ready: usize,
+    scheduler: Box<dyn Scheduler + Send>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct GroupProcInfo {
+    present: bool,
+    group: GroupId,
+    priority: Option<u8>,
+    affinity: CpuMask,
+}
+
+impl Group {
+    fn new(parent: Option<GroupId>, weight: u64, scheduler: Box<dyn Scheduler + Send>) -> Self {
+        Group {
+            parent,
+            children: Vec::new(),
+            ready: BTreeSet::new(),
+            weight,
+            vruntime: 0,
+            own_vruntime: 0,
+            min_vruntime: 0,
+            nr_own: 0,
+            nr_ready: 0,
+            scheduler,
+        }
+    }
+}
+
+/// Virtual runtime of one tick at `weight`.
+fn tick_vruntime(weight: u64) -> u64 {
+    DEFAULT_WEIGHT * DEFAULT_WEIGHT / weight
+}
+
+impl<I: Instrument> Scheduler for GroupScheduler<I> {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+        self.instrument.on_push(tid);
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.inner.lock().pop(cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let need_reschedule = self.inner.lock().tick(current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    /// Set the priority of a thread in the scheduler of its group.
+    fn set_priority(&self, tid: usize, priority: u8) {
+        let mut inner = self.inner.lock();
+        expand(&mut inner.infos, tid);
+        let info = &mut inner.infos[tid];
+        info.priority = Some(priority);
+        let group = info.group;
+        inner.group(group).scheduler.set_priority(tid, priority);
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        let mut inner = self.inner.lock();
+        expand(&mut inner.infos, tid);
+        let info = &mut inner.infos[tid];
+        info.affinity = mask;
+        let group = info.group;
+        inner.group(group).scheduler.set_affinity(tid, mask);
+    }
+}
+
+impl GroupScheduler {
+    pub fn new(root: impl Scheduler + Send, min_granularity: usize) -> Self {
+        Self::with_instrument(root, min_granularity, NoInstrument)
+    }
+}
+
+impl<I: Instrument> GroupScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    ///
+    /// The threads of the root group are scheduled by `root`.
+    /// A thread runs at least `min_granularity` ticks before another group preempts it.
+    pub fn with_instrument(
+        root: impl Scheduler + Send,
+        min_granularity: usize,
+        instrument: I,
+    ) -> Self {
+        let inner = GroupSchedulerInner {
+            min_granularity: min_granularity as u64,
+            groups: vec![Some(Group::new(None, DEFAULT_WEIGHT, Box::new(root)))],
+            infos: Vec::default(),
+        };
+        GroupScheduler {
+            inner: Mutex::new(inner),
+            instrument,
+        }
+    }
+
+    /// Create a group under `parent`, its threads scheduled by `scheduler`.
+    ///
+    /// Weights are relative to the siblings, the threads of the parent weigh 1024.
+    /// The id of a destroyed group may be given to a new one.
+    pub fn create_group(
+        &self,
+        parent: GroupId,
+        weight: u64,
+        scheduler: impl Scheduler + Send,
+    ) -> GroupId {
+        assert!(weight > 0, "group: weight must be positive");
+        let mut inner = self.inner.lock();
+        let vruntime = inner.group(parent).min_vruntime;
+        let mut group = Group::new(Some(parent), weight, Box::new(scheduler));
+        group.vruntime = vruntime;
+        let id = match inner.groups.iter().position(Option::is_none) {
+            Some(i) => {
+                inner.groups[i] = Some(group);
+                GroupId(i)
+            }
+            None => {
+                inner.groups.push(Some(group));
+                GroupId(inner.groups.len() - 1)
+            }
+        };
+        inner.group_mut(parent).children.push(id);
+        trace!("group create {:?} under {:?} weight {}", id, parent, weight);
+        id
+    }
+
+    /// Destroy `group`, moving its threads and child groups to its parent.
+    pub fn destroy_group(&self, group: GroupId) {
+        assert_ne!(group, GroupId::ROOT, "group: can not destroy the root");
+        self.inner.lock().destroy_group(group);
+    }
+
+    /// Change the weight of `group`.
+    pub fn set_weight(&self, group: GroupId, weight: u64) {
+        assert!(weight > 0, "group: weight must be positive");
+        self.inner.lock().group_mut(group).weight = weight;
+    }
+
+    /// Move a thread to `group`, keeping its priority and affinity.
+    pub fn move_thread(&self, tid: Tid, group: GroupId) {
+        self.inner.lock().move_thread(tid, group);
+    }
+
+    /// The group of a thread.
+    pub fn group_of(&self, tid: Tid) -> GroupId {
+        let inner = self.inner.lock();
+        inner
+            .infos
+            .get(tid)
+            .map_or(GroupId::ROOT, |info| info.group)
+    }
+}
+
+impl GroupSchedulerInner {
+    fn group(&self, id: GroupId) -> &Group {
+        match self.groups.get(id.0) {
+            Some(Some(group)) => group,
+            _ => panic!("group: no such group {:?}", id),
+        }
+    }
+
+    fn group_mut(&mut self, id: GroupId) -> &mut Group {
+        match self.groups.get_mut(id.0) {
+            Some(Some(group)) => group,
+            _ => panic!("group: no such group {:?}", id),
+        }
+    }
+
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if info.present {
+            return;
+        }
+        info.present = true;
+        let id = info.group;
+        trace!("group push {} to {:?}", tid, id);
+        let group = self.group_mut(id);
+        if group.nr_own == 0 {
+            // a waking entity starts from the others, it gets no credit for sleeping
+            group.own_vruntime = group.own_vruntime.max(group.min_vruntime);
+        }
+        group.nr_own += 1;
+        group.scheduler.push(tid);
+        self.add_ready(id);
+    }
+
+    /// Count a new ready thread in `id` and its ancestors.
+    fn add_ready(&mut self, mut id: GroupId) {
+        loop {
+            let group = self.group_mut(id);
+            group.nr_ready += 1;
+            let (parent, first) = (group.parent, group.nr_ready == 1);
+            let parent = match parent {
+                Some(parent) => parent,
+                None => return,
+            };
+            if first {
+                let min_vruntime = self.group(parent).min_vruntime;
+                let group = self.group_mut(id);
+                group.vruntime = group.vruntime.max(min_vruntime);
+                let vruntime = group.vruntime;
+                self.group_mut(parent).ready.insert((vruntime, id));
+            }
+            id = parent;
+        }
+    }
+
+    /// Uncount a ready thread in `id` and its ancestors.
+    fn sub_ready(&mut self, mut id: GroupId) {
+        self.group_mut(id).nr_own -= 1;
+        loop {
+            let group = self.group_mut(id);
+            group.nr_ready -= 1;
+            let (parent, last, vruntime) = (group.parent, group.nr_ready == 0, group.vruntime);
+            let parent = match parent {
+                Some(parent) => parent,
+                None => return,
+            };
+            if last {
+                self.group_mut(parent).ready.remove(&(vruntime, id));
+            }
+            id = parent;
+        }
+    }
+
+    /// Add `delta` to the virtual runtime of the group `id`, which has a parent,
+    /// keeping it in order among the ready children of its parent.
+    fn add_vruntime(&mut self, id: GroupId, parent: GroupId, delta: u64) -> u64 {
+        let group = self.group_mut(id);
+        let (old, ready) = (group.vruntime, group.nr_ready != 0);
+        group.vruntime += delta;
+        let vruntime = group.vruntime;
+        if ready {
+            let siblings = &mut self.group_mut(parent).ready;
+            siblings.remove(&(old, id));
+            siblings.insert((vruntime, id));
+        }
+        vruntime
+    }
+
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        let tid = self.pop_from(GroupId::ROOT, cpu_id)?;
+        self.infos[tid].present = false;
+        let group = self.infos[tid].group;
+        self.sub_ready(group);
+        trace!("group pop {} from {:?}", tid, group);
+        Some(tid)
+    }
+
+    /// Pick a thread in the subtree of `id`, trying its entities by virtual runtime
+    /// until one has a thread allowed on `cpu_id`.
+    ///
+    /// The threads of the group come before a child group of the same virtual runtime.
+    fn pop_from(&mut self, id: GroupId, cpu_id: usize) -> Option<Tid> {
+        let mut own = self.group(id).nr_own != 0;
+        let mut next = self.group(id).ready.iter().next().cloned();
+        loop {
+            let own_vruntime = self.group(id).own_vruntime;
+            let (vruntime, tid) = match next {
+                Some((vruntime, child)) if !own || vruntime < own_vruntime => {
+                    let after = (Excluded((vruntime, child)), Unbounded);
+                    next = self.group(id).ready.range(after).next().cloned();
+                    (vruntime, self.pop_from(child, cpu_id))
+                }
+                _ if own => {
+                    own = false;
+                    (own_vruntime, self.group(id).scheduler.pop(cpu_id))
+                }
+                _ => return None,
+            };
+            if tid.is_some() {
+                let group = self.group_mut(id);
+                group.min_vruntime = group.min_vruntime.max(vruntime);
+                return tid;
+            }
+        }
+    }
+
+    /// Charge the tick to the groups of `current`, from its own up to the root.
+    /// Reschedule if its group's scheduler says so, or if an entity on its path
+    /// got more than the granularity ahead of a ready sibling.
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        let mut id = self.infos[current].group;
+        let granularity = self.min_granularity * tick_vruntime(DEFAULT_WEIGHT);
+        let group = self.group_mut(id);
+        group.own_vruntime += tick_vruntime(DEFAULT_WEIGHT);
+        let mut need_reschedule = group.scheduler.tick(current);
+        let own_vruntime = group.own_vruntime;
+        need_reschedule |= self.is_behind(id, None, own_vruntime, granularity);
+        loop {
+            let group = self.group(id);
+            let parent = match group.parent {
+                Some(parent) => parent,
+                None => return need_reschedule,
+            };
+            let vruntime = self.add_vruntime(id, parent, tick_vruntime(group.weight));
+            need_reschedule |= self.is_behind(parent, Some(id), vruntime, granularity);
+            id = parent;
+        }
+    }
+
+    /// Whether a ready entity of `id` other than `running` is more than `granularity`
+    /// behind `vruntime`. `None` stands for the threads of the group.
+    fn is_behind(
+        &self,
+        id: GroupId,
+        running: Option<GroupId>,
+        vruntime: u64,
+        granularity: u64,
+    ) -> bool {
+        let group = self.group(id);
+        let behind = |other: u64| other + granularity < vruntime;
+        if running.is_some() && group.nr_own != 0 && behind(group.own_vruntime) {
+            return true;
+        }
+        // the first ready child is the one the furthest behind
+        let first = group
+            .ready
+            .iter()
+            .find(|&&(_, child)| Some(child) != running);
+        matches!(first, Some(&(other, _)) if behind(other))
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        let info = match self.infos.get_mut(tid) {
+            Some(info) if info.present => info,
+            _ => return,
+        };
+        info.present = false;
+        let group = info.group;
+        self.group(group).scheduler.remove(tid);
+        self.sub_ready(group);
+    }
+
+    fn move_thread(&mut self, tid: Tid, group: GroupId) {
+        expand(&mut self.infos, tid);
+        // check it exists before touching the thread
+        self.group(group);
+        let info = self.infos[tid];
+        if info.group == group {
+            return;
+        }
+        self.remove(tid);
+        self.infos[tid].group = group;
+        // setting the affinity first lets the new scheduler know the thread
+        let scheduler = &self.group(group).scheduler;
+        scheduler.set_affinity(tid, info.affinity);
+        if let Some(priority) = info.priority {
+            scheduler.set_priority(tid, priority);
+        }
+        if info.present {
+            self.push(tid);
+        }
+        trace!("group move {} from {:?} to {:?}", tid, info.group, group);
+    }
+
+    fn destroy_group(&mut self, id: GroupId) {
+        let parent = self.group(id).parent.unwrap();
+        let members: Vec<Tid> = (0..self.infos.len())
+            .filter(|&tid| self.infos[tid].group == id)
+            .collect();
+        for tid in members {
+            self.move_thread(tid, parent);
+        }
+        // the ready threads of the children stay counted in the parent
+        let children = core::mem::take(&mut self.group_mut(id).children);
+        let vruntime = self.group(id).vruntime;
+        let min_vruntime = self.group(parent).min_vruntime;
+        self.group_mut(parent).ready.remove(&(vruntime, id));
+        for &child in children.iter() {
+            let group = self.group_mut(child);
+            group.parent = Some(parent);
+            group.vruntime = group.vruntime.max(min_vruntime);
+            let (vruntime, ready) = (group.vruntime, group.nr_ready != 0);
+            if ready {
+                self.group_mut(parent).ready.insert((vruntime, child));
+            }
+        }
+        let parent_group = self.group_mut(parent);
+        parent_group.children.retain(|&child| child != id);
+        parent_group.children.extend(children);
+        self.groups[id.0] = None;
+        trace!("group destroy {:?}", id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{about, share};
+    use super::*;
+
+    fn group() -> GroupScheduler {
+        GroupScheduler::new(RRScheduler::new(1), 1)
+    }
+
+    #[test]
+    fn siblings_share_by_weight() {
+        let s = group();
+        let light = s.create_group(GroupId::ROOT, 1024, RRScheduler::new(1));
+        let heavy = s.create_group(GroupId::ROOT, 2048, RRScheduler::new(1));
+        s.move_thread(0, light);
+        s.move_thread(1, heavy);
+        let runs = share(&s, 2, 3000);
+        assert!(about(runs[1], runs[0], 2.0, 0.05), "{:?}", runs);
+    }
+
+    #[test]
+    fn own_threads_weigh_as_one_child() {
+        let s = group();
+        let g = s.create_group(GroupId::ROOT, 1024, RRScheduler::new(1));
+        for tid in 1..4 {
+            s.move_thread(tid, g);
+        }
+        let runs = share(&s, 4, 3000);
+        assert!(
+            about(runs[0], runs[1] + runs[2] + runs[3], 1.0, 0.05),
+            "{:?}",
+            runs
+        );
+        assert!(about(runs[1], runs[3], 1.0, 0.1), "{:?}", runs);
+    }
+
+    #[test]
+    fn move_keeps_priority_and_affinity() {
+        let s = GroupScheduler::new(O1Scheduler::new(), 1);
+        let g = s.create_group(GroupId::ROOT, 1024, O1Scheduler::new());
+        s.set_priority(2, 39);
+        s.set_affinity(3, CpuMask::single(1));
+        for tid in 1..4 {
+            s.move_thread(tid, g);
+            s.push(tid);
+        }
+        // 2 was nice -20 in the root, it still is in the group
+        assert_eq!(s.pop(0), Some(2));
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), None);
+        assert_eq!(s.pop(1), Some(3));
+    }
+
+    #[test]
+    fn destroy_moves_threads_and_children_up() {
+        let s = group();
+        let a = s.create_group(GroupId::ROOT, 1024, RRScheduler::new(1));
+        let b = s.create_group(a, 1024, RRScheduler::new(1));
+        s.move_thread(1, a);
+        s.move_thread(2, b);
+        s.push(1);
+        s.push(2);
+        s.destroy_group(a);
+        assert_eq!(s.group_of(1), GroupId::ROOT);
+        assert_eq!(s.group_of(2), b);
+        {
+            let inner = s.inner.lock();
+            assert_eq!(inner.group(b).parent, Some(GroupId::ROOT));
+            assert_eq!(inner.group(GroupId::ROOT).children, [b]);
+            assert_eq!(inner.group(GroupId::ROOT).nr_ready, 2);
+        }
+        let mut popped = [s.pop(0).unwrap(), s.pop(0).unwrap()];
+        popped.sort_unstable();
+        assert_eq!(popped, [1, 2]);
+        assert_eq!(s.pop(0), None);
+        assert_eq!(s.inner.lock().group(GroupId::ROOT).nr_ready, 0);
+        // the slot of the destroyed group is used again
+        assert_eq!(s.create_group(b, 1024, RRScheduler::new(1)), a);
+    }
+
+    #[test]
+    fn pop_skips_groups_with_no_thread_allowed() {
+        let s = group();
+        let a = s.create_group(GroupId::ROOT, 1024, RRScheduler::new(1));
+        let b = s.create_group(GroupId::ROOT, 1024, RRScheduler::new(1));
+        s.move_thread(1, a);
+        s.move_thread(2, b);
+        s.set_affinity(1, CpuMask::single(1));
+        s.push(1);
+        s.push(2);
+        // a comes first, but its only thread may not run on CPU 0
+        assert_eq!(s.pop(0), Some(2));
+        assert_eq!(s.pop(0), None);
+        assert_eq!(s.pop(1), Some(1));
+    }
+}
@@ -0,0 +1,289 @@
+//! Lock-free round robin scheduler
+//!
+//! Same policy as [`RRScheduler`], but the ready queue is a bounded MPMC ring buffer
+//! and the state of each thread lives in a slot allocated up front,
+//! so `push` never waits on a lock held by another CPU, even from an interrupt handler.
+//! Pops should run with interrupts disabled, as they are between claiming a cell and
+//! releasing it.
+//!
+//! A thread removed while queued leaves a tombstone, skipped when it reaches the head.
+
+use super::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Not in the ready queue.
+const IDLE: usize = 0;
+/// In the ready queue.
+const QUEUED: usize = 1;
+/// Still in the ready queue, but removed.
+const REMOVED: usize = 2;
+
+pub struct LockFreeRRScheduler<I: Instrument = NoInstrument> {
+    max_time_slice: usize,
+    infos: Vec<LockFreeRRProcInfo>,
+    queue: ArrayQueue,
+    instrument: I,
+}
+
+#[derive(Default)]
+struct LockFreeRRProcInfo {
+    state: AtomicUsize,
+    rest_slice: AtomicUsize,
+    affinity: AtomicUsize,
+}
+
+impl<I: Instrument> Scheduler for LockFreeRRScheduler<I> {
+    fn push(&self, tid: usize) {
+        let info = self.info(tid);
+        if info.rest_slice.load(Ordering::Relaxed) == 0 {
+            info.rest_slice
+                .store(self.max_time_slice, Ordering::Relaxed);
+        }
+        // a removed thread is still queued, its tombstone is revived
+        let mut state = info.state.load(Ordering::Acquire);
+        loop {
+            if state == QUEUED {
+                warn!("lock-free rr: thread {} pushed twice", tid);
+                return;
+            }
+            match info.state.compare_exchange_weak(
+                state,
+                QUEUED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(actual) => state = actual,
+            }
+        }
+        if state == IDLE {
+            self.queue.push(tid);
+        }
+        trace!("lock-free rr push {}", tid);
+        self.instrument.on_push(tid);
+        platform::wake_idle_cpu();
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        // each thread not allowed here goes back to the tail, at most once per slot
+        for _ in 0..self.infos.len() {
+            let tid = self.queue.pop()?;
+            let info = &self.infos[tid];
+            if info.state.load(Ordering::Acquire) == QUEUED
+                && !CpuMask(info.affinity.load(Ordering::Relaxed)).contains(cpu_id)
+            {
+                self.queue.push(tid);
+                continue;
+            }
+            match info.state.swap(IDLE, Ordering::AcqRel) {
+                QUEUED => {
+                    trace!("lock-free rr pop {}", tid);
+                    self.instrument.on_pop(tid);
+                    self.instrument.on_switch(cpu_id, tid);
+                    return Some(tid);
+                }
+                _ => trace!("lock-free rr drop removed {}", tid),
+            }
+        }
+        None
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let info = self.info(current_tid);
+        let rest = info.rest_slice.load(Ordering::Relaxed);
+        if rest > 0 {
+            info.rest_slice.store(rest - 1, Ordering::Relaxed);
+        } else {
+            warn!("current process rest_slice = 0, need reschedule")
+        }
+        let need_reschedule = rest <= 1;
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    fn set_priority(&self, _tid: usize, _priority: u8) {}
+    fn remove(&self, tid: usize) {
+        let info = self.info(tid);
+        let _ = info
+            .state
+            .compare_exchange(QUEUED, REMOVED, Ordering::AcqRel, Ordering::Acquire);
+    }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.info(tid).affinity.store(mask.0, Ordering::Relaxed);
+    }
+}
+
+impl LockFreeRRScheduler {
+    pub fn new(max_time_slice: usize, max_thread_num: usize) -> Self {
+        Self::with_instrument(max_time_slice, max_thread_num, NoInstrument)
+    }
+}
+
+impl<I: Instrument> LockFreeRRScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    ///
+    /// Thread ids must be below `max_thread_num`.
+    pub fn with_instrument(max_time_slice: usize, max_thread_num: usize, instrument: I) -> Self {
+        let infos = (0..max_thread_num)
+            .map(|_| LockFreeRRProcInfo {
+                affinity: AtomicUsize::new(CpuMask::ALL.0),
+                ..Default::default()
+            })
+            .collect();
+        LockFreeRRScheduler {
+            max_time_slice,
+            infos,
+            queue: ArrayQueue::new(max_thread_num * 2),
+            instrument,
+        }
+    }
+
+    fn info(&self, tid: Tid) -> &LockFreeRRProcInfo {
+        self.infos.get(tid).unwrap_or_else(|| {
+            panic!(
+                "lock-free rr: thread {} over the limit of {}",
+                tid,
+                self.infos.len()
+            )
+        })
+    }
+}
+
+/// Bounded MPMC queue of Dmitry Vyukov.
+///
+/// Each cell has a sequence number telling whose turn it is,
+/// producers and consumers claim positions by CAS and never wait on each other
+/// unless the queue is full or empty.
+/// It has twice the cells it may hold, so that a push only meets a cell not yet released
+/// by a pop after a whole lap of the others.
+struct ArrayQueue {
+    cells: Vec<Cell>,
+    mask: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+struct Cell {
+    sequence: AtomicUsize,
+    value: AtomicUsize,
+}
+
+impl ArrayQueue {
+    /// A queue of at least `capacity` cells.
+    fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1).next_power_of_two();
+        let cells = (0..capacity)
+            .map(|i| Cell {
+                sequence: AtomicUsize::new(i),
+                value: AtomicUsize::new(0),
+            })
+            .collect();
+        ArrayQueue {
+            cells,
+            mask: capacity - 1,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Push `value`, the queue must not be full.
+    fn push(&self, value: usize) {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let cell = &self.cells[pos & self.mask];
+            let sequence = cell.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(pos) as isize;
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        cell.value.store(value, Ordering::Relaxed);
+                        cell.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return;
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else {
+                // another push took the cell, or a pop lagging a lap behind
+                // has yet to release it: the slots bound the number of queued threads,
+                // so the queue is never full
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<usize> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let cell = &self.cells[pos & self.mask];
+            let sequence = cell.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(pos.wrapping_add(1)) as isize;
+            if diff == 0 {
+                match self.head.compare_exchange_weak(
+                    pos,
+                    pos.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let value = cell.value.load(Ordering::Relaxed);
+                        let next = pos.wrapping_add(self.mask + 1);
+                        cell.sequence.store(next, Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(actual) => pos = actual,
+                }
+            } else if diff < 0 {
+                return None;
+            } else {
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_across_wraparound() {
+        let queue = ArrayQueue::new(4);
+        let mut next = 0;
+        for round in 0..10 {
+            for i in 0..3 {
+                queue.push(round * 3 + i);
+            }
+            for _ in 0..3 {
+                assert_eq!(queue.pop(), Some(next));
+                next += 1;
+            }
+            assert_eq!(queue.pop(), None);
+        }
+    }
+
+    #[test]
+    fn removed_then_pushed_is_revived() {
+        let s = LockFreeRRScheduler::new(2, 4);
+        s.push(0);
+        s.push(1);
+        s.remove(0);
+        // its tombstone is still queued, so it keeps its place
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), None);
+    }
+
+    #[test]
+    fn disallowed_goes_back_to_the_tail() {
+        let s = LockFreeRRScheduler::new(2, 4);
+        s.set_affinity(0, CpuMask::single(1));
+        s.push(0);
+        s.push(1);
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), None);
+        assert_eq!(s.pop(1), Some(0));
+    }
+}
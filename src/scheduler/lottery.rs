@@ -0,0 +1,413 @@
+//! Lottery scheduler
+//!
+//! Each task holds tickets. A random ticket is drawn among the ready tasks,
+//! its holder is selected to run. This is the randomized counterpart of stride scheduling.
+//!
+//! A task blocked on another one can transfer its tickets to it until it is woken.
+//! A group of tasks can hold tickets in its own currency: the group is funded with
+//! base tickets, shared among its active tasks in proportion to their own tickets.
+
+use super::*;
+use crate::rng::{Rng, XorShiftRng};
+use alloc::vec;
+
+/// Tickets of a task whose priority was never set.
+const DEFAULT_TICKETS: u64 = 100;
+
+/// A currency in which tasks hold tickets.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CurrencyId(usize);
+
+impl CurrencyId {
+    /// The currency of base tickets, which every task starts in.
+    pub const BASE: CurrencyId = CurrencyId(0);
+}
+
+pub struct LotteryScheduler<I: Instrument = NoInstrument, R: Rng = XorShiftRng> {
+    inner: Mutex<LotterySchedulerInner<R>>,
+    instrument: I,
+}
+
+struct LotterySchedulerInner<R> {
+    max_time_slice: usize,
+    rng: R,
+    infos: Vec<LotteryProcInfo>,
+    /// Base tickets funding each currency, the base currency is unused.
+    currencies: Vec<u64>,
+    /// Active tickets held in each currency.
+    active: Vec<u64>,
+    queue: Vec<Tid>,
+}
+
+#[derive(Debug, Clone)]
+struct LotteryProcInfo {
+    present: bool,
+    rest_slice: usize,
+    tickets: u64,
+    currency: CurrencyId,
+    /// The task holding our tickets while we are blocked.
+    transfer_to: Option<Tid>,
+    /// The tasks lending us their tickets.
+    clients: Vec<Tid>,
+    affinity: CpuMask,
+}
+
+impl Default for LotteryProcInfo {
+    fn default() -> Self {
+        LotteryProcInfo {
+            present: false,
+            rest_slice: 0,
+            tickets: DEFAULT_TICKETS,
+            currency: CurrencyId::BASE,
+            transfer_to: None,
+            clients: Vec::new(),
+            affinity: CpuMask::ALL,
+        }
+    }
+}
+
+impl<I: Instrument, R: Rng> Scheduler for LotteryScheduler<I, R> {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+        self.instrument.on_push(tid);
+        platform::wake_idle_cpu();
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.inner.lock().pop(cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let need_reschedule = self.inner.lock().tick(current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    /// Set the number of tickets of a thread, in its currency.
+    fn set_priority(&self, tid: usize, priority: u8) {
+        self.inner.lock().set_tickets(tid, priority as u64);
+    }
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.inner.lock().set_affinity(tid, mask);
+    }
+}
+
+impl LotteryScheduler {
+    pub fn new(max_time_slice: usize, seed: u64) -> Self {
+        Self::with_instrument(max_time_slice, seed, NoInstrument)
+    }
+}
+
+impl<I: Instrument> LotteryScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    pub fn with_instrument(max_time_slice: usize, seed: u64, instrument: I) -> Self {
+        Self::with_rng(max_time_slice, XorShiftRng::new(seed), instrument)
+    }
+}
+
+impl<I: Instrument, R: Rng> LotteryScheduler<I, R> {
+    /// Create a scheduler drawing tickets from `rng`.
+    pub fn with_rng(max_time_slice: usize, rng: R, instrument: I) -> Self {
+        let inner = LotterySchedulerInner {
+            max_time_slice,
+            rng,
+            infos: Vec::default(),
+            currencies: vec![0],
+            active: vec![0],
+            queue: Vec::default(),
+        };
+        LotteryScheduler {
+            inner: Mutex::new(inner),
+            instrument,
+        }
+    }
+
+    /// Set the number of tickets of a thread, in its currency.
+    pub fn set_tickets(&self, tid: Tid, tickets: u64) {
+        self.inner.lock().set_tickets(tid, tickets);
+    }
+
+    /// Lend the tickets of `client`, blocked on `server`, to `server` until `client` is pushed.
+    pub fn transfer(&self, client: Tid, server: Tid) {
+        self.inner.lock().transfer(client, server);
+    }
+
+    /// Create a currency funded with `funding` base tickets.
+    pub fn new_currency(&self, funding: u64) -> CurrencyId {
+        let mut inner = self.inner.lock();
+        inner.currencies.push(funding);
+        inner.active.push(0);
+        CurrencyId(inner.currencies.len() - 1)
+    }
+
+    /// Change the base tickets funding `currency`.
+    pub fn fund(&self, currency: CurrencyId, funding: u64) {
+        assert_ne!(
+            currency,
+            CurrencyId::BASE,
+            "lottery: can not fund the base currency"
+        );
+        self.inner.lock().currencies[currency.0] = funding;
+    }
+
+    /// Move a thread to `currency`, keeping its number of tickets.
+    pub fn set_currency(&self, tid: Tid, currency: CurrencyId) {
+        let mut inner = self.inner.lock();
+        assert!(
+            currency.0 < inner.currencies.len(),
+            "lottery: no such currency"
+        );
+        inner.update(tid, |info| info.currency = currency);
+    }
+}
+
+impl<R: Rng> LotterySchedulerInner<R> {
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        if self.infos[tid].present {
+            return;
+        }
+        let max_time_slice = self.max_time_slice;
+        // woken, take our tickets back
+        self.update(tid, |info| {
+            info.transfer_to = None;
+            info.present = true;
+            if info.rest_slice == 0 {
+                info.rest_slice = max_time_slice;
+            }
+        });
+        self.queue.push(tid);
+        trace!("lottery push {}", tid);
+    }
+
+    /// Tickets of ready tasks and lent tickets are active, they fund their currency.
+    fn is_active(info: &LotteryProcInfo) -> bool {
+        info.present || info.transfer_to.is_some()
+    }
+
+    /// Change the info of `tid` with `f`, keeping the active tickets
+    /// and the clients of each server up to date.
+    fn update(&mut self, tid: Tid, f: impl FnOnce(&mut LotteryProcInfo)) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if Self::is_active(info) {
+            self.active[info.currency.0] -= info.tickets;
+        }
+        let server = info.transfer_to;
+        f(info);
+        if Self::is_active(info) {
+            self.active[info.currency.0] += info.tickets;
+        }
+        let new_server = info.transfer_to;
+        if server != new_server {
+            if let Some(server) = server {
+                self.infos[server].clients.retain(|&t| t != tid);
+            }
+            if let Some(server) = new_server {
+                self.infos[server].clients.push(tid);
+            }
+        }
+    }
+
+    /// Value of the own tickets of `tid`, in base tickets.
+    fn base_value(&self, tid: Tid) -> u64 {
+        let info = &self.infos[tid];
+        match info.currency {
+            CurrencyId::BASE => info.tickets,
+            CurrencyId(c) if self.active[c] == 0 => 0,
+            CurrencyId(c) => {
+                (self.currencies[c] as u128 * info.tickets as u128 / self.active[c] as u128) as u64
+            }
+        }
+    }
+
+    /// Value of the tickets `tid` draws with, its own and the ones lent to it,
+    /// 0 if it may not run on `cpu_id`.
+    fn value(&self, tid: Tid, cpu_id: usize) -> u64 {
+        let info = &self.infos[tid];
+        if !info.affinity.contains(cpu_id) {
+            return 0;
+        }
+        let lent: u64 = info.clients.iter().map(|&t| self.base_value(t)).sum();
+        self.base_value(tid) + lent
+    }
+
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        // only threads allowed on this CPU take part in the draw
+        let first = self
+            .queue
+            .iter()
+            .position(|&tid| self.infos[tid].affinity.contains(cpu_id))?;
+        let total: u64 = self.queue.iter().map(|&tid| self.value(tid, cpu_id)).sum();
+        let (index, value) = if total == 0 {
+            (first, 0)
+        } else {
+            let mut winner = self.rng.below(total);
+            let mut found = None;
+            for (i, &tid) in self.queue.iter().enumerate() {
+                let value = self.value(tid, cpu_id);
+                if winner < value {
+                    found = Some((i, value));
+                    break;
+                }
+                winner -= value;
+            }
+            found.unwrap()
+        };
+        let tid = self.queue.remove(index);
+        self.update(tid, |info| info.present = false);
+        trace!("lottery pop {} with {}/{} tickets", tid, value, total);
+        Some(tid)
+    }
+
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        let rest = &mut self.infos[current].rest_slice;
+        if *rest > 0 {
+            *rest -= 1;
+        } else {
+            warn!("current process rest_slice = 0, need reschedule")
+        }
+        *rest == 0
+    }
+
+    fn set_tickets(&mut self, tid: Tid, tickets: u64) {
+        self.update(tid, |info| info.tickets = tickets);
+        trace!("lottery {} tickets = {}", tid, tickets);
+    }
+
+    fn transfer(&mut self, client: Tid, server: Tid) {
+        assert_ne!(
+            client, server,
+            "lottery: can not transfer tickets to itself"
+        );
+        expand(&mut self.infos, client.max(server));
+        self.remove(client);
+        self.update(client, |info| info.transfer_to = Some(server));
+        trace!("lottery {} lends its tickets to {}", client, server);
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        if tid >= self.infos.len() {
+            return;
+        }
+        let present = self.infos[tid].present;
+        self.update(tid, |info| {
+            info.transfer_to = None;
+            info.present = false;
+        });
+        if present {
+            self.queue.retain(|&t| t != tid);
+        }
+    }
+
+    fn set_affinity(&mut self, tid: Tid, mask: CpuMask) {
+        expand(&mut self.infos, tid);
+        self.infos[tid].affinity = mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::about;
+    use super::*;
+
+    /// Pop and push back `draws` times, counting the picks of each thread.
+    fn draws(s: &LotteryScheduler, n: usize, draws: usize) -> Vec<usize> {
+        let mut picks = vec![0; n];
+        for _ in 0..draws {
+            let tid = s.pop(0).unwrap();
+            picks[tid] += 1;
+            s.push(tid);
+        }
+        picks
+    }
+
+    fn picks(seed: u64) -> Vec<Tid> {
+        let s = LotteryScheduler::new(1, seed);
+        for tid in 0..4 {
+            s.set_tickets(tid, 100 * (tid as u64 + 1));
+            s.push(tid);
+        }
+        (0..64)
+            .map(|_| {
+                let tid = s.pop(0).unwrap();
+                s.push(tid);
+                tid
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_same_picks() {
+        assert_eq!(picks(7), picks(7));
+        assert_ne!(picks(7), picks(8));
+    }
+
+    #[test]
+    fn picks_follow_tickets() {
+        let s = LotteryScheduler::new(1, 1);
+        for tid in 0..3 {
+            s.set_tickets(tid, 100 * (tid as u64 + 1));
+            s.push(tid);
+        }
+        let picks = draws(&s, 3, 12000);
+        assert!(about(picks[1], picks[0], 2.0, 0.1));
+        assert!(about(picks[2], picks[0], 3.0, 0.1));
+    }
+
+    #[test]
+    fn transfer_lends_tickets_until_pushed() {
+        let s = LotteryScheduler::new(1, 2);
+        for tid in 0..3 {
+            s.push(tid);
+        }
+        // 1 blocks on 0
+        s.transfer(1, 0);
+        {
+            let inner = s.inner.lock();
+            assert_eq!(inner.value(0, 0), 2 * DEFAULT_TICKETS);
+            assert_eq!(inner.active[0], 3 * DEFAULT_TICKETS);
+        }
+        let picks = draws(&s, 3, 6000);
+        assert_eq!(picks[1], 0);
+        assert!(about(picks[0], picks[2], 2.0, 0.1));
+        // woken, 1 takes its tickets back
+        s.push(1);
+        let inner = s.inner.lock();
+        assert_eq!(inner.value(0, 0), DEFAULT_TICKETS);
+        assert!(inner.infos[0].clients.is_empty());
+        assert_eq!(inner.active[0], 3 * DEFAULT_TICKETS);
+    }
+
+    #[test]
+    fn currency_shares_its_funding() {
+        let s = LotteryScheduler::new(1, 3);
+        let currency = s.new_currency(100);
+        s.set_currency(1, currency);
+        s.set_currency(2, currency);
+        s.set_tickets(1, 1);
+        s.set_tickets(2, 3);
+        for tid in 0..3 {
+            s.push(tid);
+        }
+        {
+            let inner = s.inner.lock();
+            let values: Vec<u64> = (0..3).map(|tid| inner.base_value(tid)).collect();
+            assert_eq!(values, [100, 25, 75]);
+        }
+        let picks = draws(&s, 3, 8000);
+        assert!(about(picks[0], picks[1] + picks[2], 1.0, 0.1));
+        assert!(about(picks[2], picks[1], 3.0, 0.15));
+        // the funding goes to the active threads alone
+        s.remove(2);
+        assert_eq!(s.inner.lock().base_value(1), 100);
+        s.fund(currency, 50);
+        assert_eq!(s.inner.lock().base_value(1), 50);
+    }
+}
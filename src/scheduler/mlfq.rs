@@ -0,0 +1,280 @@
+//! Multi-level feedback queue scheduler
+//!
+//! There is a round robin queue per level, level 0 is the highest.
+//! A task using up the time slice of its level is demoted to the next one,
+//! a task blocking before that stays where it is.
+//! A task is preempted as soon as a task of a higher level is ready.
+//! Every boost period all tasks are moved back to level 0, so none starves.
+//! The boost period is measured on a clock, however many CPUs tick.
+
+use super::*;
+use crate::asynchronous::stats::Clock;
+use alloc::collections::VecDeque;
+
+pub struct MlfqScheduler<I: Instrument = NoInstrument> {
+    inner: Mutex<MlfqSchedulerInner>,
+    clock: &'static dyn Clock,
+    instrument: I,
+}
+
+struct MlfqSchedulerInner {
+    time_slices: Vec<usize>,
+    boost_period: u64,
+    /// Clock time of the last boost.
+    last_boost: u64,
+    queues: Vec<VecDeque<Tid>>,
+    infos: Vec<MlfqProcInfo>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct MlfqProcInfo {
+    present: bool,
+    level: usize,
+    /// Ticks used at the current level, kept across blocking.
+    used: usize,
+    affinity: CpuMask,
+}
+
+impl<I: Instrument> Scheduler for MlfqScheduler<I> {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+        self.instrument.on_push(tid);
+        platform::wake_idle_cpu();
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.inner.lock().pop(cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let cpu = platform::cpu_id();
+        let need_reschedule = self.inner.lock().tick(cpu, current_tid, self.clock.now());
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    /// The level of a task only depends on its behaviour.
+    fn set_priority(&self, _tid: usize, _priority: u8) {}
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.inner.lock().set_affinity(tid, mask);
+    }
+}
+
+impl MlfqScheduler {
+    pub fn new(time_slices: &[usize], boost_period: u64, clock: &'static dyn Clock) -> Self {
+        Self::with_instrument(time_slices, boost_period, clock, NoInstrument)
+    }
+}
+
+impl<I: Instrument> MlfqScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    ///
+    /// There is a level per entry of `time_slices`, highest first.
+    /// All tasks are boosted to the highest level every `boost_period` ticks of `clock`,
+    /// never if it is 0.
+    pub fn with_instrument(
+        time_slices: &[usize],
+        boost_period: u64,
+        clock: &'static dyn Clock,
+        instrument: I,
+    ) -> Self {
+        assert!(!time_slices.is_empty(), "mlfq: need at least one level");
+        assert!(
+            time_slices.iter().all(|&slice| slice > 0),
+            "mlfq: time slices must be positive"
+        );
+        let inner = MlfqSchedulerInner {
+            time_slices: time_slices.to_vec(),
+            boost_period,
+            last_boost: clock.now(),
+            queues: time_slices.iter().map(|_| VecDeque::new()).collect(),
+            infos: Vec::default(),
+        };
+        MlfqScheduler {
+            inner: Mutex::new(inner),
+            clock,
+            instrument,
+        }
+    }
+}
+
+impl MlfqSchedulerInner {
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if info.present {
+            return;
+        }
+        info.present = true;
+        self.queues[info.level].push_back(tid);
+        trace!("mlfq push {} at level {}", tid, info.level);
+    }
+
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        let infos = &self.infos;
+        let allowed = |&tid: &Tid| infos[tid].affinity.contains(cpu_id);
+        let (level, i) = self
+            .queues
+            .iter()
+            .enumerate()
+            .find_map(|(level, queue)| queue.iter().position(allowed).map(|i| (level, i)))?;
+        let tid = self.queues[level].remove(i).unwrap();
+        self.infos[tid].present = false;
+        trace!("mlfq pop {} at level {}", tid, level);
+        Some(tid)
+    }
+
+    fn tick(&mut self, cpu: usize, current: Tid, now: u64) -> bool {
+        expand(&mut self.infos, current);
+        if self.boost_period != 0 && now >= self.last_boost + self.boost_period {
+            self.last_boost = now;
+            self.boost();
+        }
+
+        let lowest = self.time_slices.len() - 1;
+        let info = &mut self.infos[current];
+        info.used += 1;
+        if info.used < self.time_slices[info.level] {
+            let level = info.level;
+            return self.has_higher(cpu, level);
+        }
+        info.used = 0;
+        if info.level < lowest {
+            info.level += 1;
+            trace!("mlfq demote {} to level {}", current, info.level);
+        }
+        true
+    }
+
+    /// Whether a task allowed on `cpu` is ready above `level`.
+    fn has_higher(&self, cpu: usize, level: usize) -> bool {
+        let infos = &self.infos;
+        self.queues[..level]
+            .iter()
+            .any(|queue| queue.iter().any(|&tid| infos[tid].affinity.contains(cpu)))
+    }
+
+    /// Move every task to level 0, keeping the order of the queues.
+    fn boost(&mut self) {
+        for info in self.infos.iter_mut() {
+            info.level = 0;
+            info.used = 0;
+        }
+        let (top, rest) = self.queues.split_at_mut(1);
+        for queue in rest {
+            top[0].extend(queue.drain(..));
+        }
+        trace!("mlfq boost");
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        if let Some(info) = self.infos.get_mut(tid) {
+            if info.present {
+                info.present = false;
+                self.queues[info.level].retain(|&t| t != tid);
+            }
+        }
+    }
+
+    fn set_affinity(&mut self, tid: Tid, mask: CpuMask) {
+        expand(&mut self.infos, tid);
+        self.infos[tid].affinity = mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::TestClock;
+    use super::*;
+
+    fn mlfq(time_slices: &[usize], boost_period: u64) -> (MlfqScheduler, &'static TestClock) {
+        let clock = TestClock::leak();
+        (MlfqScheduler::new(time_slices, boost_period, clock), clock)
+    }
+
+    fn level(s: &MlfqScheduler, tid: Tid) -> usize {
+        s.inner.lock().infos[tid].level
+    }
+
+    #[test]
+    fn demoted_after_using_its_slice() {
+        let (s, _) = mlfq(&[1, 2, 4], 0);
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        assert!(s.tick(0));
+        assert_eq!(level(&s, 0), 1);
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        assert!(!s.tick(0));
+        assert!(s.tick(0));
+        assert_eq!(level(&s, 0), 2);
+        // the lowest level keeps it
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        for _ in 0..3 {
+            assert!(!s.tick(0));
+        }
+        assert!(s.tick(0));
+        assert_eq!(level(&s, 0), 2);
+    }
+
+    #[test]
+    fn blocking_keeps_the_level() {
+        let (s, _) = mlfq(&[2, 4], 0);
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        assert!(!s.tick(0));
+        // blocks, then wakes with the tick it used
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        assert_eq!(level(&s, 0), 0);
+        assert!(s.tick(0));
+        assert_eq!(level(&s, 0), 1);
+    }
+
+    #[test]
+    fn higher_level_preempts() {
+        let (s, _) = mlfq(&[1, 8], 0);
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        assert!(s.tick(0));
+        s.push(0);
+        assert_eq!(s.pop(0), Some(0));
+        assert!(!s.tick(0));
+        // a level 0 task wakes up
+        s.push(1);
+        assert!(s.tick(0));
+        s.push(0);
+        assert_eq!(s.pop(0), Some(1));
+    }
+
+    #[test]
+    fn boost_once_per_period_on_any_number_of_cpus() {
+        let (s, clock) = mlfq(&[1, 100], 10);
+        // 4 waits at level 1
+        s.push(4);
+        assert_eq!(s.pop(0), Some(4));
+        assert!(s.tick(4));
+        s.push(4);
+        for tid in 0..4 {
+            s.push(tid);
+        }
+        for cpu in 0..4 {
+            assert_eq!(s.pop(cpu), Some(cpu));
+        }
+        let mut inner = s.inner.lock();
+        for now in 1..15 {
+            clock.set(now);
+            for cpu in 0..4 {
+                inner.tick(cpu, cpu, now);
+            }
+            assert_eq!(inner.infos[4].level == 0, now >= 10, "at {}", now);
+        }
+        assert_eq!(inner.last_boost, 10);
+    }
+}
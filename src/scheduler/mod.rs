@@ -3,14 +3,39 @@ use alloc::{collections::BinaryHeap, vec::Vec};
 use log::*;
 use spin::Mutex;
 
+use crate::instrument::{Instrument, NoInstrument};
+use crate::platform;
+
+pub use self::bandwidth::{BandwidthGroupId, BandwidthScheduler, ThrottleStats};
+pub use self::cfs::CfsScheduler;
+pub use self::classed::{ClassedScheduler, Policy};
+pub use self::edf::{AdmissionError, EdfScheduler};
+pub use self::group::{GroupId, GroupScheduler};
+pub use self::lock_free_rr::LockFreeRRScheduler;
+pub use self::lottery::{CurrencyId, LotteryScheduler};
+pub use self::mlfq::MlfqScheduler;
 pub use self::o1::O1Scheduler;
+pub use self::per_cpu::{LoadMetric, PerCpuScheduler};
+pub use self::rm::{RateMonotonicScheduler, Schedulability};
 pub use self::rr::RRScheduler;
 pub use self::stride::StrideScheduler;
 pub use self::work_stealing::WorkStealingScheduler;
 
+mod bandwidth;
+mod cfs;
+mod classed;
+mod edf;
+mod group;
+mod lock_free_rr;
+mod lottery;
+mod mlfq;
 mod o1;
+mod per_cpu;
+mod rm;
 mod rr;
 mod stride;
+#[cfg(test)]
+mod test_util;
 mod work_stealing;
 
 type Tid = usize;
@@ -28,6 +53,61 @@ pub trait Scheduler: 'static {
     fn set_priority(&self, tid: Tid, priority: u8);
     /// remove a thread in ready queue.
     fn remove(&self, tid: Tid);
+    /// Restrict a thread to the CPUs in `mask`. It is never popped on another CPU.
+    fn set_affinity(&self, tid: Tid, mask: CpuMask);
+}
+
+/// A set of CPUs, bit `i` standing for CPU `i`.
+///
+/// CPUs beyond the width of the mask are always allowed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CpuMask(pub usize);
+
+impl CpuMask {
+    pub const ALL: CpuMask = CpuMask(usize::MAX);
+
+    pub fn single(cpu: usize) -> Self {
+        CpuMask(1 << cpu)
+    }
+
+    pub fn contains(self, cpu: usize) -> bool {
+        !matches!(self.0.checked_shr(cpu as u32), Some(mask) if mask & 1 == 0)
+    }
+}
+
+impl Default for CpuMask {
+    fn default() -> Self {
+        CpuMask::ALL
+    }
+}
+
+/// Parameters of a periodic real-time thread, in ticks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RtParams {
+    /// Execution time per period.
+    pub runtime: u64,
+    pub period: u64,
+    /// Relative deadline of each job, at most the period.
+    pub deadline: u64,
+}
+
+impl RtParams {
+    /// A thread whose deadline is the end of its period.
+    pub fn new(runtime: u64, period: u64) -> Self {
+        RtParams {
+            runtime,
+            period,
+            deadline: period,
+        }
+    }
+
+    pub fn with_deadline(self, deadline: u64) -> Self {
+        RtParams { deadline, ..self }
+    }
+
+    fn is_valid(&self) -> bool {
+        0 < self.runtime && self.runtime <= self.deadline && self.deadline <= self.period
+    }
 }
 
 fn expand<T: Default + Clone>(vec: &mut Vec<T>, id: usize) {
@@ -1,68 +1,434 @@
 //! O(1) scheduler introduced in Linux 2.6
 //!
-//! Two queues are maintained, one is active, another is inactive.
-//! Take the first task from the active queue to run. When it is empty, swap active and inactive queues.
+//! Two priority arrays are maintained, one is active, another is expired.
+//! Each has a FIFO list per priority and a bitmap of the non-empty ones,
+//! so the highest priority task is found with a find-first-set.
+//! A task using up its time slice moves to the expired array, unless it is interactive.
+//! When the active array is empty, swap active and expired arrays.
+//!
+//! Priorities 0 to 99 are real-time, 100 to 139 map to nice -20 to 19, lower runs first.
+//! Normal tasks get a bonus of up to 5 levels either way from their average sleep time.
+//! Time slices assume one tick per millisecond.
 
 use super::*;
+use alloc::collections::VecDeque;
+
+const MAX_RT_PRIO: usize = 100;
+const MAX_PRIO: usize = 140;
+const MAX_USER_PRIO: usize = MAX_PRIO - MAX_RT_PRIO;
+const DEFAULT_PRIO: usize = MAX_RT_PRIO + 20;
+const BITMAP_WORDS: usize = (MAX_PRIO - 1) / 64 + 1;
+
+const MIN_TIMESLICE: usize = 5;
+const DEF_TIMESLICE: usize = 100;
+const MAX_BONUS: usize = 10;
+const MAX_SLEEP_AVG: usize = 1000;
+/// How far above its static priority a task must be boosted to count as interactive.
+const INTERACTIVE_DELTA: isize = 2;
+/// Ticks the expired array may wait per ready task before interactive tasks expire too.
+const STARVATION_LIMIT: usize = MAX_SLEEP_AVG;
 
-pub struct O1Scheduler {
+pub struct O1Scheduler<I: Instrument = NoInstrument> {
     inner: Mutex<O1SchedulerInner>,
+    instrument: I,
 }
 
 struct O1SchedulerInner {
-    active_queue: usize,
-    queues: [Vec<Tid>; 2],
+    /// Scheduler clock, advancing on every tick.
+    now: usize,
+    active: usize,
+    arrays: [PrioArray; 2],
+    /// When the first task expired since the last swap.
+    expired_since: Option<usize>,
+    infos: Vec<O1ProcInfo>,
+}
+
+struct PrioArray {
+    nr_active: usize,
+    bitmap: [u64; BITMAP_WORDS],
+    queues: Vec<VecDeque<Tid>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct O1ProcInfo {
+    /// The array the task is queued in.
+    array: Option<usize>,
+    static_prio: usize,
+    /// Static priority with the interactivity bonus applied.
+    prio: usize,
+    time_slice: usize,
+    sleep_avg: usize,
+    last_ran: usize,
+    /// Used up its time slice, goes to the expired array on the next push.
+    expired: bool,
+    affinity: CpuMask,
+}
+
+impl Default for O1ProcInfo {
+    fn default() -> Self {
+        O1ProcInfo {
+            array: None,
+            static_prio: DEFAULT_PRIO,
+            prio: DEFAULT_PRIO,
+            time_slice: 0,
+            sleep_avg: 0,
+            last_ran: 0,
+            expired: false,
+            affinity: CpuMask::ALL,
+        }
+    }
 }
 
-impl Scheduler for O1Scheduler {
+impl O1ProcInfo {
+    fn is_rt(&self) -> bool {
+        self.static_prio < MAX_RT_PRIO
+    }
+
+    fn nice(&self) -> isize {
+        self.static_prio as isize - DEFAULT_PRIO as isize
+    }
+
+    /// -5 to 5 levels from the average sleep time.
+    fn bonus(&self) -> isize {
+        (self.sleep_avg * MAX_BONUS / MAX_SLEEP_AVG) as isize - (MAX_BONUS / 2) as isize
+    }
+
+    fn effective_prio(&self) -> usize {
+        if self.is_rt() {
+            return self.static_prio;
+        }
+        let prio = self.static_prio as isize - self.bonus();
+        prio.max(MAX_RT_PRIO as isize).min(MAX_PRIO as isize - 1) as usize
+    }
+
+    /// Nice 0 tasks need a bonus of 2, nice -20 ones are always, nice 19 ones never interactive.
+    fn is_interactive(&self) -> bool {
+        let delta = self.nice() * MAX_BONUS as isize / 40 + INTERACTIVE_DELTA;
+        self.bonus() >= delta
+    }
+
+    /// 800 ticks at nice -20, 100 at nice 0, 5 at nice 19.
+    fn base_time_slice(&self) -> usize {
+        let scale = if self.static_prio < DEFAULT_PRIO {
+            DEF_TIMESLICE * 4
+        } else {
+            DEF_TIMESLICE
+        };
+        let slice = scale * (MAX_PRIO - self.static_prio.max(MAX_RT_PRIO)) / (MAX_USER_PRIO / 2);
+        slice.max(MIN_TIMESLICE)
+    }
+}
+
+impl PrioArray {
+    fn new() -> Self {
+        PrioArray {
+            nr_active: 0,
+            bitmap: [0; BITMAP_WORDS],
+            queues: (0..MAX_PRIO).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    fn enqueue(&mut self, tid: Tid, prio: usize) {
+        self.queues[prio].push_back(tid);
+        self.bitmap[prio / 64] |= 1 << (prio % 64);
+        self.nr_active += 1;
+    }
+
+    fn dequeue(&mut self, tid: Tid, prio: usize) {
+        let queue = &mut self.queues[prio];
+        if let Some(i) = queue.iter().position(|&t| t == tid) {
+            queue.remove(i);
+            self.nr_active -= 1;
+            if queue.is_empty() {
+                self.bitmap[prio / 64] &= !(1 << (prio % 64));
+            }
+        }
+    }
+
+    /// Pop the first task of the highest priority passing `filter`.
+    fn pop_where(&mut self, filter: impl Fn(Tid) -> bool) -> Option<Tid> {
+        for (i, &word) in self.bitmap.iter().enumerate() {
+            let mut word = word;
+            while word != 0 {
+                let prio = i * 64 + word.trailing_zeros() as usize;
+                word &= word - 1;
+                if let Some(tid) = self.queues[prio].iter().cloned().find(|&tid| filter(tid)) {
+                    self.dequeue(tid, prio);
+                    return Some(tid);
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<I: Instrument> Scheduler for O1Scheduler<I> {
     fn push(&self, tid: usize) {
         self.inner.lock().push(tid);
+        self.instrument.on_push(tid);
+        platform::wake_idle_cpu();
     }
-    fn pop(&self, _cpu_id: usize) -> Option<usize> {
-        self.inner.lock().pop()
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.inner.lock().pop(cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
     }
     fn tick(&self, current_tid: usize) -> bool {
-        self.inner.lock().tick(current_tid)
+        let need_reschedule = self.inner.lock().tick(current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    /// Priorities 0 to 39 map to nice 19 to -20, so 19 is nice 0.
+    fn set_priority(&self, tid: usize, priority: u8) {
+        let prio = MAX_PRIO - 1 - priority.min(39) as usize;
+        self.inner.lock().set_static_prio(tid, prio);
     }
-    fn set_priority(&self, _tid: usize, _priority: u8) {}
-    fn remove(&self, _tid: usize) {
-        unimplemented!()
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.inner.lock().set_affinity(tid, mask);
     }
 }
 
 impl O1Scheduler {
     pub fn new() -> Self {
+        Self::with_instrument(NoInstrument)
+    }
+}
+
+impl Default for O1Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: Instrument> O1Scheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    pub fn with_instrument(instrument: I) -> Self {
         let inner = O1SchedulerInner {
-            active_queue: 0,
-            queues: [Vec::new(), Vec::new()],
+            now: 0,
+            active: 0,
+            arrays: [PrioArray::new(), PrioArray::new()],
+            expired_since: None,
+            infos: Vec::default(),
         };
         O1Scheduler {
             inner: Mutex::new(inner),
+            instrument,
         }
     }
+
+    /// Set the static priority of a thread, 0 to 99 for real-time and 100 to 139 for normal.
+    pub fn set_static_prio(&self, tid: Tid, prio: u8) {
+        let prio = (prio as usize).min(MAX_PRIO - 1);
+        self.inner.lock().set_static_prio(tid, prio);
+    }
 }
 
 impl O1SchedulerInner {
     fn push(&mut self, tid: Tid) {
-        let inactive_queue = 1 - self.active_queue;
-        self.queues[inactive_queue].push(tid);
-        trace!("o1 push {}", tid - 1);
-    }
-
-    fn pop(&mut self) -> Option<Tid> {
-        let ret = match self.queues[self.active_queue].pop() {
-            Some(tid) => return Some(tid),
-            None => {
-                // active queue is empty, swap 'em
-                self.active_queue = 1 - self.active_queue;
-                self.queues[self.active_queue].pop()
+        expand(&mut self.infos, tid);
+        let now = self.now;
+        let info = &mut self.infos[tid];
+        if info.array.is_some() {
+            return;
+        }
+        let array = if info.expired {
+            info.expired = false;
+            1 - self.active
+        } else {
+            if info.time_slice == 0 {
+                // a new task starts without credit
+                info.time_slice = info.base_time_slice();
+            } else {
+                // woken up, credit the time asleep
+                let slept = now - info.last_ran;
+                info.sleep_avg = (info.sleep_avg + slept).min(MAX_SLEEP_AVG);
             }
+            info.prio = info.effective_prio();
+            self.active
         };
+        info.array = Some(array);
+        self.arrays[array].enqueue(tid, info.prio);
+        trace!("o1 push {} prio {} array {}", tid, info.prio, array);
+    }
+
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        if self.arrays[self.active].nr_active == 0 {
+            // active array is empty, swap 'em
+            self.active = 1 - self.active;
+            self.expired_since = None;
+        }
+        let infos = &self.infos;
+        let allowed = |tid: Tid| infos[tid].affinity.contains(cpu_id);
+        let active = self.active;
+        // if no active task may run here, take an expired one rather than idling
+        let ret = match self.arrays[active].pop_where(allowed) {
+            Some(tid) => Some(tid),
+            None => self.arrays[1 - active].pop_where(allowed),
+        };
+        if let Some(tid) = ret {
+            let info = &mut self.infos[tid];
+            info.array = None;
+            info.last_ran = self.now;
+        }
         trace!("o1 pop {:?}", ret);
         ret
     }
 
-    fn tick(&mut self, _current: Tid) -> bool {
+    /// Whether tasks waited too long in the expired array, interactive tasks expire then too.
+    fn expired_starving(&self) -> bool {
+        let nr_running = self.arrays[0].nr_active + self.arrays[1].nr_active + 1;
+        matches!(self.expired_since,
+            Some(since) if self.now - since > STARVATION_LIMIT * nr_running)
+    }
+
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        self.now += 1;
+        let now = self.now;
+        let starving = self.expired_starving();
+        let info = &mut self.infos[current];
+        info.last_ran = now;
+        info.sleep_avg = info.sleep_avg.saturating_sub(1);
+        if info.time_slice > 0 {
+            info.time_slice -= 1;
+        }
+        if info.time_slice > 0 {
+            return false;
+        }
+        info.prio = info.effective_prio();
+        info.time_slice = info.base_time_slice();
+        // real-time tasks and interactive ones go round the active array again
+        if !info.is_rt() && (!info.is_interactive() || starving) {
+            info.expired = true;
+            self.expired_since.get_or_insert(now);
+        }
         true
     }
+
+    fn set_static_prio(&mut self, tid: Tid, prio: usize) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        info.static_prio = prio;
+        let old_prio = info.prio;
+        info.prio = info.effective_prio();
+        if let Some(array) = info.array {
+            let new_prio = info.prio;
+            self.arrays[array].dequeue(tid, old_prio);
+            self.arrays[array].enqueue(tid, new_prio);
+        }
+        trace!("o1 {} static prio = {}", tid, prio);
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        if let Some(info) = self.infos.get_mut(tid) {
+            if let Some(array) = info.array.take() {
+                self.arrays[array].dequeue(tid, info.prio);
+            }
+        }
+    }
+
+    fn set_affinity(&mut self, tid: Tid, mask: CpuMask) {
+        expand(&mut self.infos, tid);
+        self.infos[tid].affinity = mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_dequeues_the_task() {
+        let s = O1Scheduler::new();
+        s.push(1);
+        s.push(2);
+        s.push(3);
+        s.remove(2);
+        s.remove(7);
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), Some(3));
+        assert_eq!(s.pop(0), None);
+        // removed from its list, so pushing it again queues it once
+        s.push(2);
+        assert_eq!(s.pop(0), Some(2));
+        assert_eq!(s.pop(0), None);
+    }
+
+    #[test]
+    fn set_priority_requeues_the_task() {
+        let s = O1Scheduler::new();
+        s.push(1);
+        s.push(2);
+        s.push(3);
+        // nice -20 while queued
+        s.set_priority(3, 39);
+        assert_eq!(s.inner.lock().infos[3].static_prio, MAX_RT_PRIO);
+        // real-time goes before every normal task
+        s.set_static_prio(2, 10);
+        assert_eq!(s.pop(0), Some(2));
+        assert_eq!(s.pop(0), Some(3));
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), None);
+    }
+
+    #[test]
+    fn expired_tasks_wait_for_the_array_swap() {
+        let s = O1Scheduler::new();
+        s.push(1);
+        s.push(2);
+        assert_eq!(s.pop(0), Some(1));
+        for _ in 1..DEF_TIMESLICE {
+            assert!(!s.tick(1));
+        }
+        assert!(s.tick(1));
+        // a CPU hog is not interactive, so it goes to the expired array
+        s.push(1);
+        assert_eq!(s.pop(0), Some(2));
+        for _ in 0..10 {
+            assert!(!s.tick(2));
+        }
+        // still has time left, runs again before the expired one
+        s.push(2);
+        assert_eq!(s.pop(0), Some(2));
+        while !s.tick(2) {}
+        s.push(2);
+        // active array is empty, the expired one takes over in FIFO order
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), Some(2));
+        assert_eq!(s.pop(0), None);
+    }
+
+    #[test]
+    fn sleepers_get_a_bonus_and_stay_active() {
+        let s = O1Scheduler::new();
+        s.push(1);
+        s.push(2);
+        assert_eq!(s.pop(0), Some(1));
+        s.tick(1);
+        // 1 blocks while 2 runs for a second
+        assert_eq!(s.pop(0), Some(2));
+        for _ in 0..MAX_SLEEP_AVG {
+            s.tick(2);
+        }
+        s.push(3);
+        s.push(1);
+        assert_eq!(s.inner.lock().infos[1].prio, DEFAULT_PRIO - MAX_BONUS / 2);
+        assert_eq!(s.inner.lock().infos[3].prio, DEFAULT_PRIO + MAX_BONUS / 2);
+        assert_eq!(s.pop(0), Some(1));
+        while !s.tick(1) {}
+        // interactive, goes round the active array again ahead of 3
+        s.push(1);
+        let inner = s.inner.lock();
+        assert_eq!(inner.infos[1].array, Some(inner.active));
+        drop(inner);
+        assert_eq!(s.pop(0), Some(1));
+        assert_eq!(s.pop(0), Some(3));
+        assert_eq!(s.pop(0), None);
+    }
 }
@@ -0,0 +1,532 @@
+//! Per-CPU scheduler
+//!
+//! Each CPU has its own instance of a policy, such as [`RRScheduler`] or [`StrideScheduler`],
+//! and pops only from it, so CPUs do not contend on a single lock.
+//! Fairness is that of the policy, within each CPU.
+//!
+//! A waking thread goes back to the CPU it last ran on while its cache may be warm,
+//! unless that CPU is clearly busier than the others.
+//! Every balance period, a CPU pulls threads from the busiest one until their loads are even,
+//! and a CPU running out of threads pulls one right away.
+//! A migrated thread is pushed to its new instance like a waking one, which places it
+//! among the threads already there, as [`CfsScheduler`] and [`StrideScheduler::catch_up`] do.
+//!
+//! The CPU, priority and affinity of each thread are atomics of their own.
+//! The CPU of a thread only changes with the run queue of its current CPU locked,
+//! or from none to the first one with that run queue locked.
+
+use super::*;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::MutexGuard;
+
+/// No CPU or priority yet.
+const NONE: usize = usize::MAX;
+
+/// How the load of a CPU is measured for balancing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoadMetric {
+    /// Number of ready threads.
+    QueueLength,
+    /// Sum of the priorities of the ready threads, each counting at least 1.
+    Weight,
+}
+
+pub struct PerCpuScheduler<S: Scheduler, I: Instrument = NoInstrument> {
+    cpus: Vec<RunQueue<S>>,
+    infos: Vec<PerCpuProcInfo>,
+    balance_period: usize,
+    metric: LoadMetric,
+    instrument: I,
+}
+
+struct RunQueue<S> {
+    scheduler: S,
+    /// Threads queued in `scheduler`, with their weights.
+    queued: Mutex<BTreeMap<Tid, usize>>,
+    /// Mirrors of `queued`, read without locking it.
+    nr_running: AtomicUsize,
+    weight: AtomicUsize,
+    /// Ticks until the next balancing.
+    ticks: AtomicUsize,
+}
+
+struct PerCpuProcInfo {
+    /// The CPU queuing the thread, or the last it ran on.
+    cpu: AtomicUsize,
+    priority: AtomicUsize,
+    affinity: AtomicUsize,
+}
+
+impl PerCpuProcInfo {
+    fn new() -> Self {
+        PerCpuProcInfo {
+            cpu: AtomicUsize::new(NONE),
+            priority: AtomicUsize::new(NONE),
+            affinity: AtomicUsize::new(CpuMask::ALL.0),
+        }
+    }
+
+    fn cpu(&self) -> Option<usize> {
+        match self.cpu.load(Ordering::Relaxed) {
+            NONE => None,
+            cpu => Some(cpu),
+        }
+    }
+
+    fn priority(&self) -> Option<u8> {
+        match self.priority.load(Ordering::Relaxed) {
+            NONE => None,
+            priority => Some(priority as u8),
+        }
+    }
+
+    fn affinity(&self) -> CpuMask {
+        CpuMask(self.affinity.load(Ordering::Relaxed))
+    }
+
+    fn weight(&self) -> usize {
+        self.priority().unwrap_or(1).max(1) as usize
+    }
+}
+
+impl<S: Scheduler, I: Instrument> Scheduler for PerCpuScheduler<S, I> {
+    fn push(&self, tid: usize) {
+        let cpu = self.select_cpu(self.info(tid));
+        if self.enqueue(tid, cpu) {
+            trace!("per-cpu: cpu{} push thread {}", cpu, tid);
+            self.instrument.on_push(tid);
+        }
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let rq = &self.cpus[cpu_id];
+        let local = rq.dequeue(&mut rq.queued.lock(), cpu_id);
+        // our queue is unlocked before locking another, see `balance`
+        let ret = local.or_else(|| self.pull(cpu_id));
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let cpu = platform::cpu_id();
+        let rq = &self.cpus[cpu];
+        let need_reschedule = rq.scheduler.tick(current_tid);
+        if self.balance_period != 0
+            && rq.ticks.fetch_add(1, Ordering::Relaxed) + 1 >= self.balance_period
+        {
+            rq.ticks.store(0, Ordering::Relaxed);
+            self.balance(cpu);
+        }
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    fn set_priority(&self, tid: usize, priority: u8) {
+        let info = self.info(tid);
+        info.priority.store(priority as usize, Ordering::Relaxed);
+        if let Some((cpu, mut queued)) = self.lock_queue(tid) {
+            let rq = &self.cpus[cpu];
+            if let Some(weight) = queued.get_mut(&tid) {
+                rq.weight.fetch_sub(*weight, Ordering::Relaxed);
+                rq.weight.fetch_add(info.weight(), Ordering::Relaxed);
+                *weight = info.weight();
+            }
+            rq.scheduler.set_priority(tid, priority);
+        }
+    }
+    fn remove(&self, tid: usize) {
+        if let Some((cpu, mut queued)) = self.lock_queue(tid) {
+            self.cpus[cpu].take(&mut queued, tid);
+        }
+    }
+    /// The affinity is set in every instance, as the thread may migrate to any.
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.info(tid).affinity.store(mask.0, Ordering::Relaxed);
+        for rq in self.cpus.iter() {
+            rq.scheduler.set_affinity(tid, mask);
+        }
+    }
+}
+
+impl<S: Scheduler> PerCpuScheduler<S> {
+    pub fn new(
+        core_num: usize,
+        max_thread_num: usize,
+        balance_period: usize,
+        metric: LoadMetric,
+        new_scheduler: impl FnMut(usize) -> S,
+    ) -> Self {
+        Self::with_instrument(
+            core_num,
+            max_thread_num,
+            balance_period,
+            metric,
+            new_scheduler,
+            NoInstrument,
+        )
+    }
+}
+
+impl<S: Scheduler, I: Instrument> PerCpuScheduler<S, I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    ///
+    /// `new_scheduler` creates the instance of each CPU from its id.
+    /// Thread ids must be below `max_thread_num`.
+    /// Each CPU balances its load every `balance_period` of its ticks, never if it is 0.
+    pub fn with_instrument(
+        core_num: usize,
+        max_thread_num: usize,
+        balance_period: usize,
+        metric: LoadMetric,
+        new_scheduler: impl FnMut(usize) -> S,
+        instrument: I,
+    ) -> Self {
+        assert!(core_num > 0, "per-cpu: need at least one CPU");
+        let cpus = (0..core_num)
+            .map(new_scheduler)
+            .map(RunQueue::new)
+            .collect();
+        let infos = (0..max_thread_num).map(|_| PerCpuProcInfo::new()).collect();
+        PerCpuScheduler {
+            cpus,
+            infos,
+            balance_period,
+            metric,
+            instrument,
+        }
+    }
+
+    /// The instance of `cpu`.
+    pub fn scheduler(&self, cpu: usize) -> &S {
+        &self.cpus[cpu].scheduler
+    }
+
+    /// Number of ready threads queued on `cpu`.
+    pub fn nr_running(&self, cpu: usize) -> usize {
+        self.cpus[cpu].nr_running.load(Ordering::Relaxed)
+    }
+
+    fn info(&self, tid: Tid) -> &PerCpuProcInfo {
+        self.infos.get(tid).unwrap_or_else(|| {
+            panic!(
+                "per-cpu: thread {} over the limit of {}",
+                tid,
+                self.infos.len()
+            )
+        })
+    }
+
+    /// Lock the run queue of the CPU of `tid`, which can not change until it is unlocked.
+    fn lock_queue(&self, tid: Tid) -> Option<(usize, MutexGuard<'_, BTreeMap<Tid, usize>>)> {
+        let info = self.info(tid);
+        loop {
+            let cpu = info.cpu()?;
+            let queued = self.cpus[cpu].queued.lock();
+            // migrated in the meantime
+            if info.cpu() == Some(cpu) {
+                return Some((cpu, queued));
+            }
+        }
+    }
+
+    fn load(&self, cpu: usize) -> usize {
+        let rq = &self.cpus[cpu];
+        match self.metric {
+            LoadMetric::QueueLength => rq.nr_running.load(Ordering::Relaxed),
+            LoadMetric::Weight => rq.weight.load(Ordering::Relaxed),
+        }
+    }
+
+    fn weight(&self, info: &PerCpuProcInfo) -> usize {
+        match self.metric {
+            LoadMetric::QueueLength => 1,
+            LoadMetric::Weight => info.weight(),
+        }
+    }
+
+    /// The last CPU of the thread if allowed and not busier than the least loaded one
+    /// by more than the thread itself, otherwise the least loaded allowed CPU.
+    fn select_cpu(&self, info: &PerCpuProcInfo) -> usize {
+        let affinity = info.affinity();
+        let allowed = (0..self.cpus.len()).filter(|&cpu| affinity.contains(cpu));
+        let least = match allowed.min_by_key(|&cpu| self.load(cpu)) {
+            Some(cpu) => cpu,
+            None => {
+                warn!("per-cpu: thread allowed on no CPU");
+                return info.cpu().unwrap_or(0);
+            }
+        };
+        match info.cpu() {
+            Some(last)
+                if affinity.contains(last)
+                    && self.load(last) <= self.load(least) + self.weight(info) =>
+            {
+                last
+            }
+            _ => least,
+        }
+    }
+
+    /// Queue `tid` on `cpu`, unless it is queued already.
+    ///
+    /// The queue of its last CPU stays locked along with the one of `cpu`,
+    /// so that a concurrent push of the same thread sees it queued.
+    fn enqueue(&self, tid: Tid, cpu: usize) -> bool {
+        let rq = &self.cpus[cpu];
+        let info = self.info(tid);
+        loop {
+            let last = info.cpu();
+            // both queues are locked in the order of their CPUs, see `balance`
+            let (mut queued, last_queued) = match last {
+                Some(last) if last < cpu => {
+                    let last_queued = self.cpus[last].queued.lock();
+                    (rq.queued.lock(), Some(last_queued))
+                }
+                Some(last) if last > cpu => {
+                    let queued = rq.queued.lock();
+                    (queued, Some(self.cpus[last].queued.lock()))
+                }
+                _ => (rq.queued.lock(), None),
+            };
+            // migrated in the meantime
+            if info.cpu() != last {
+                continue;
+            }
+            if last_queued.as_ref().unwrap_or(&queued).contains_key(&tid) {
+                return false;
+            }
+            // a first push racing with another, on another CPU
+            let last_cpu = last.unwrap_or(NONE);
+            if info
+                .cpu
+                .compare_exchange(last_cpu, cpu, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+            {
+                continue;
+            }
+            rq.insert(&mut queued, tid, info, last != Some(cpu));
+            return true;
+        }
+    }
+
+    /// The allowed CPU with the highest load above the one of `cpu`.
+    fn busiest(&self, cpu: usize) -> Option<usize> {
+        let load = self.load(cpu);
+        (0..self.cpus.len())
+            .filter(|&other| other != cpu && self.load(other) > load)
+            .max_by_key(|&other| self.load(other))
+    }
+
+    /// Take a thread for the idle `cpu` to run now, from the busiest CPU
+    /// having one allowed here.
+    fn pull(&self, cpu: usize) -> Option<Tid> {
+        let load = self.load(cpu);
+        let mut busier: Vec<(usize, usize)> = (0..self.cpus.len())
+            .filter(|&other| other != cpu)
+            .map(|other| (self.load(other), other))
+            .filter(|&(other_load, _)| other_load > load)
+            .collect();
+        busier.sort_unstable_by(|a, b| b.cmp(a));
+        busier.into_iter().find_map(|(_, busiest)| {
+            let rq = &self.cpus[busiest];
+            let mut queued = rq.queued.lock();
+            let tid = rq.dequeue(&mut queued, cpu)?;
+            self.info(tid).cpu.store(cpu, Ordering::Relaxed);
+            trace!(
+                "per-cpu: cpu{} pull thread {} from cpu{}",
+                cpu,
+                tid,
+                busiest
+            );
+            Some(tid)
+        })
+    }
+
+    /// Move threads from the busiest CPU to `cpu` while that evens their loads.
+    ///
+    /// The threads are picked in the queue of the busiest CPU without popping them,
+    /// which would count as having run them.
+    fn balance(&self, cpu: usize) {
+        let busiest = match self.busiest(cpu) {
+            Some(busiest) => busiest,
+            None => return,
+        };
+        // both queues are locked in the order of their CPUs, as the busiest may balance too
+        let (src, dst) = (&self.cpus[busiest], &self.cpus[cpu]);
+        let (mut from, mut to) = if busiest < cpu {
+            let from = src.queued.lock();
+            (from, dst.queued.lock())
+        } else {
+            let to = dst.queued.lock();
+            (src.queued.lock(), to)
+        };
+        // each move brings the loads closer, so this ends
+        loop {
+            let imbalance = self.load(busiest).saturating_sub(self.load(cpu));
+            let candidate = from.keys().cloned().find(|&tid| {
+                let info = self.info(tid);
+                info.affinity().contains(cpu) && self.weight(info) < imbalance
+            });
+            let tid = match candidate {
+                Some(tid) => tid,
+                None => break,
+            };
+            src.take(&mut from, tid);
+            let info = self.info(tid);
+            info.cpu.store(cpu, Ordering::Relaxed);
+            dst.insert(&mut to, tid, info, true);
+            trace!(
+                "per-cpu: cpu{} balance thread {} from cpu{}",
+                cpu,
+                tid,
+                busiest
+            );
+        }
+    }
+}
+
+impl<S: Scheduler> RunQueue<S> {
+    fn new(scheduler: S) -> Self {
+        RunQueue {
+            scheduler,
+            queued: Mutex::new(BTreeMap::new()),
+            nr_running: AtomicUsize::new(0),
+            weight: AtomicUsize::new(0),
+            ticks: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queue `tid` in the instance, `queued` being our locked queue.
+    fn insert(
+        &self,
+        queued: &mut BTreeMap<Tid, usize>,
+        tid: Tid,
+        info: &PerCpuProcInfo,
+        migrated: bool,
+    ) {
+        if queued.insert(tid, info.weight()).is_some() {
+            return;
+        }
+        self.nr_running.fetch_add(1, Ordering::Relaxed);
+        self.weight.fetch_add(info.weight(), Ordering::Relaxed);
+        self.scheduler.push(tid);
+        // the instance only knows the priority if it was set while the thread was there
+        if let (true, Some(priority)) = (migrated, info.priority()) {
+            self.scheduler.set_priority(tid, priority);
+        }
+    }
+
+    /// Take `tid` out of the instance if queued, `queued` being our locked queue.
+    fn take(&self, queued: &mut BTreeMap<Tid, usize>, tid: Tid) {
+        if let Some(weight) = queued.remove(&tid) {
+            self.scheduler.remove(tid);
+            self.nr_running.fetch_sub(1, Ordering::Relaxed);
+            self.weight.fetch_sub(weight, Ordering::Relaxed);
+        }
+    }
+
+    /// Pop a thread allowed on `cpu_id`, `queued` being our locked queue.
+    fn dequeue(&self, queued: &mut BTreeMap<Tid, usize>, cpu_id: usize) -> Option<Tid> {
+        if queued.is_empty() {
+            return None;
+        }
+        let tid = self.scheduler.pop(cpu_id)?;
+        let weight = queued.remove(&tid).unwrap_or(0);
+        self.nr_running.fetch_sub(1, Ordering::Relaxed);
+        self.weight.fetch_sub(weight, Ordering::Relaxed);
+        Some(tid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn per_cpu(core_num: usize, balance_period: usize) -> PerCpuScheduler<RRScheduler> {
+        PerCpuScheduler::new(core_num, 8, balance_period, LoadMetric::QueueLength, |_| {
+            RRScheduler::new(5)
+        })
+    }
+
+    fn loads(s: &PerCpuScheduler<RRScheduler>) -> Vec<usize> {
+        (0..s.cpus.len()).map(|cpu| s.nr_running(cpu)).collect()
+    }
+
+    /// Queue `tids` on `cpu`, allowed anywhere afterwards.
+    fn queue_on(s: &PerCpuScheduler<RRScheduler>, cpu: usize, tids: core::ops::Range<Tid>) {
+        for tid in tids.clone() {
+            s.set_affinity(tid, CpuMask::single(cpu));
+            s.push(tid);
+        }
+        for tid in tids {
+            s.set_affinity(tid, CpuMask::ALL);
+        }
+    }
+
+    #[test]
+    fn waking_threads_go_back_to_their_cpu() {
+        let s = per_cpu(2, 0);
+        for tid in 0..4 {
+            s.push(tid);
+        }
+        assert_eq!(loads(&s), [2, 2]);
+        // queued once, however many pushes
+        s.push(0);
+        assert_eq!(loads(&s), [2, 2]);
+        for _ in 0..4 {
+            s.pop(1).unwrap();
+        }
+        assert_eq!(s.pop(1), None);
+        // all ran on CPU 1 last, which takes them back until it is clearly busier
+        for tid in 0..3 {
+            s.push(tid);
+        }
+        assert_eq!(loads(&s), [1, 2]);
+        s.push(3);
+        assert_eq!(loads(&s), [1, 3]);
+    }
+
+    #[test]
+    fn balance_evens_the_loads() {
+        let s = per_cpu(2, 2);
+        queue_on(&s, 1, 0..4);
+        assert_eq!(loads(&s), [0, 4]);
+        // balancing on the second tick of CPU 0
+        s.tick(9);
+        assert_eq!(loads(&s), [0, 4]);
+        s.tick(9);
+        assert_eq!(loads(&s), [2, 2]);
+        assert_eq!(s.info(0).cpu(), Some(0));
+    }
+
+    #[test]
+    fn pinned_threads_stay() {
+        let s = per_cpu(2, 1);
+        queue_on(&s, 1, 0..4);
+        for tid in 1..4 {
+            s.set_affinity(tid, CpuMask::single(1));
+        }
+        s.tick(9);
+        assert_eq!(loads(&s), [1, 3]);
+        assert_eq!(s.pop(0), Some(0));
+        // nothing allowed here to pull
+        assert_eq!(s.pop(0), None);
+        assert_eq!(loads(&s), [0, 3]);
+    }
+
+    #[test]
+    fn pull_tries_the_next_busiest() {
+        let s = per_cpu(3, 0);
+        queue_on(&s, 1, 0..3);
+        for tid in 0..3 {
+            s.set_affinity(tid, CpuMask::single(1));
+        }
+        queue_on(&s, 2, 3..4);
+        assert_eq!(loads(&s), [0, 3, 1]);
+        // CPU 1 only has threads pinned there
+        assert_eq!(s.pop(0), Some(3));
+        assert_eq!(s.info(3).cpu(), Some(0));
+        assert_eq!(loads(&s), [0, 3, 0]);
+    }
+}
@@ -0,0 +1,304 @@
+//! Rate-monotonic scheduler
+//!
+//! Periodic threads are registered with their [`RtParams`], the runtime being the
+//! worst-case execution time. The shorter the period, the higher the fixed priority,
+//! and the ready thread with the highest priority is selected to run.
+//! Threads never registered run in round robin when no periodic thread is ready.
+//!
+//! [`RateMonotonicScheduler::check_schedulable`] analyses the registered task set
+//! before it is deployed, on the host or in the kernel.
+
+use super::*;
+use alloc::collections::{BTreeSet, VecDeque};
+
+/// Result of the schedulability analysis of a task set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedulability {
+    /// Total utilization, the sum of runtime over period.
+    pub utilization: f64,
+    /// Whether the utilization is within the Liu–Layland bound `n(2^(1/n) - 1)`.
+    /// This is sufficient for threads whose deadline is their period, but not necessary.
+    pub liu_layland: bool,
+    /// Worst-case response time of each thread from highest to lowest priority,
+    /// `None` if it can miss its deadline.
+    pub response_times: Vec<(Tid, Option<u64>)>,
+}
+
+impl Schedulability {
+    /// Whether every thread meets its deadline, by the exact response-time analysis.
+    pub fn is_schedulable(&self) -> bool {
+        self.response_times.iter().all(|(_, time)| time.is_some())
+    }
+}
+
+pub struct RateMonotonicScheduler<I: Instrument = NoInstrument> {
+    inner: Mutex<RateMonotonicSchedulerInner>,
+    instrument: I,
+}
+
+struct RateMonotonicSchedulerInner {
+    infos: Vec<RmProcInfo>,
+    /// Ready periodic threads by (period, tid), highest priority first.
+    ready: BTreeSet<(u64, Tid)>,
+    background: VecDeque<Tid>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct RmProcInfo {
+    present: bool,
+    params: Option<RtParams>,
+    affinity: CpuMask,
+}
+
+impl<I: Instrument> Scheduler for RateMonotonicScheduler<I> {
+    fn push(&self, tid: usize) {
+        self.inner.lock().push(tid);
+        self.instrument.on_push(tid);
+        platform::wake_idle_cpu();
+    }
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.inner.lock().pop(cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
+    }
+    fn tick(&self, current_tid: usize) -> bool {
+        let need_reschedule = self.inner.lock().tick(current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
+    }
+    /// Priorities follow from the periods.
+    fn set_priority(&self, _tid: usize, _priority: u8) {}
+    fn remove(&self, tid: usize) {
+        self.inner.lock().remove(tid);
+    }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.inner.lock().set_affinity(tid, mask);
+    }
+}
+
+impl RateMonotonicScheduler {
+    pub fn new() -> Self {
+        Self::with_instrument(NoInstrument)
+    }
+}
+
+impl Default for RateMonotonicScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<I: Instrument> RateMonotonicScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    pub fn with_instrument(instrument: I) -> Self {
+        let inner = RateMonotonicSchedulerInner {
+            infos: Vec::default(),
+            ready: BTreeSet::default(),
+            background: VecDeque::default(),
+        };
+        RateMonotonicScheduler {
+            inner: Mutex::new(inner),
+            instrument,
+        }
+    }
+
+    /// Make `tid` a periodic thread with `params`, replacing its previous ones.
+    ///
+    /// Any valid thread is registered, [`check_schedulable`] tells whether the set still fits.
+    ///
+    /// [`check_schedulable`]: RateMonotonicScheduler::check_schedulable
+    pub fn register(&self, tid: Tid, params: RtParams) -> Result<(), AdmissionError> {
+        if !params.is_valid() {
+            return Err(AdmissionError::InvalidParams);
+        }
+        self.inner.lock().set_params(tid, Some(params));
+        Ok(())
+    }
+
+    /// Make `tid` a background thread again.
+    pub fn unregister(&self, tid: Tid) {
+        self.inner.lock().set_params(tid, None);
+    }
+
+    /// Run the Liu–Layland test and the exact response-time analysis
+    /// over the registered threads.
+    pub fn check_schedulable(&self) -> Schedulability {
+        let inner = self.inner.lock();
+        let mut tasks: Vec<(Tid, RtParams)> = inner
+            .infos
+            .iter()
+            .enumerate()
+            .filter_map(|(tid, info)| info.params.map(|params| (tid, params)))
+            .collect();
+        tasks.sort_by_key(|&(tid, params)| (params.period, tid));
+        drop(inner);
+
+        let n = tasks.len();
+        let utilization: f64 = tasks
+            .iter()
+            .map(|(_, p)| p.runtime as f64 / p.period as f64)
+            .sum();
+        // U <= n(2^(1/n) - 1) is (1 + U/n)^n <= 2, which needs no float math from std
+        let base = 1.0 + utilization / n.max(1) as f64;
+        let liu_layland = (0..n).fold(1.0, |acc, _| acc * base) <= 2.0;
+
+        let response_times = tasks
+            .iter()
+            .enumerate()
+            .map(|(i, &(tid, params))| (tid, response_time(params, &tasks[..i])))
+            .collect();
+        Schedulability {
+            utilization,
+            liu_layland,
+            response_times,
+        }
+    }
+}
+
+/// Worst-case response time of a thread preempted by `higher` ones,
+/// the fixed point of `R = C + sum(ceil(R / T_j) * C_j)`, or `None` past the deadline.
+fn response_time(params: RtParams, higher: &[(Tid, RtParams)]) -> Option<u64> {
+    let mut response = params.runtime;
+    loop {
+        let interference: u64 = higher
+            .iter()
+            // the response is at least the runtime, never 0
+            .map(|(_, hp)| ((response - 1) / hp.period + 1) * hp.runtime)
+            .sum();
+        let next = params.runtime + interference;
+        if next > params.deadline {
+            return None;
+        }
+        if next == response {
+            return Some(response);
+        }
+        response = next;
+    }
+}
+
+impl RateMonotonicSchedulerInner {
+    fn set_params(&mut self, tid: Tid, params: Option<RtParams>) {
+        expand(&mut self.infos, tid);
+        let present = self.infos[tid].present;
+        self.remove(tid);
+        self.infos[tid].params = params;
+        if present {
+            self.push(tid);
+        }
+        trace!("rm {} params = {:?}", tid, params);
+    }
+
+    fn push(&mut self, tid: Tid) {
+        expand(&mut self.infos, tid);
+        let info = &mut self.infos[tid];
+        if info.present {
+            return;
+        }
+        info.present = true;
+        match info.params {
+            Some(params) => {
+                self.ready.insert((params.period, tid));
+            }
+            None => self.background.push_back(tid),
+        }
+        trace!("rm push {}", tid);
+    }
+
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        let infos = &self.infos;
+        let allowed = |&tid: &Tid| infos[tid].affinity.contains(cpu_id);
+        let tid = match self.ready.iter().find(|&(_, tid)| allowed(tid)) {
+            Some(&key) => {
+                self.ready.remove(&key);
+                key.1
+            }
+            None => {
+                let i = self.background.iter().position(allowed)?;
+                self.background.remove(i).unwrap()
+            }
+        };
+        self.infos[tid].present = false;
+        trace!("rm pop {}", tid);
+        Some(tid)
+    }
+
+    /// Preempt the current thread as soon as one of higher priority is ready.
+    fn tick(&mut self, current: Tid) -> bool {
+        expand(&mut self.infos, current);
+        match self.infos[current].params {
+            Some(params) => {
+                matches!(self.ready.iter().next(), Some(&key) if key < (params.period, current))
+            }
+            None => !self.ready.is_empty() || !self.background.is_empty(),
+        }
+    }
+
+    fn remove(&mut self, tid: Tid) {
+        if let Some(info) = self.infos.get_mut(tid) {
+            if info.present {
+                info.present = false;
+                if let Some(params) = info.params {
+                    self.ready.remove(&(params.period, tid));
+                }
+                self.background.retain(|&t| t != tid);
+            }
+        }
+    }
+
+    fn set_affinity(&mut self, tid: Tid, mask: CpuMask) {
+        expand(&mut self.infos, tid);
+        self.infos[tid].affinity = mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyse(set: &[(u64, u64)]) -> Schedulability {
+        let s = RateMonotonicScheduler::new();
+        for (tid, &(runtime, period)) in set.iter().enumerate() {
+            s.register(tid, RtParams::new(runtime, period)).unwrap();
+        }
+        s.check_schedulable()
+    }
+
+    #[test]
+    fn within_liu_layland_bound() {
+        let result = analyse(&[(1, 4), (1, 5)]);
+        assert!(result.liu_layland);
+        assert!(result.is_schedulable());
+    }
+
+    #[test]
+    fn schedulable_above_liu_layland_bound() {
+        // U = 0.833, over the bound 0.780 of three threads
+        let result = analyse(&[(1, 4), (2, 6), (3, 12)]);
+        assert!(!result.liu_layland);
+        assert_eq!(
+            result.response_times,
+            [(0, Some(1)), (1, Some(3)), (2, Some(10))]
+        );
+        assert!(result.is_schedulable());
+    }
+
+    #[test]
+    fn unschedulable() {
+        let result = analyse(&[(2, 4), (3, 5)]);
+        assert_eq!(result.response_times, [(0, Some(2)), (1, None)]);
+        assert!(!result.is_schedulable());
+    }
+
+    #[test]
+    fn invalid_params_are_refused() {
+        let s = RateMonotonicScheduler::new();
+        assert_eq!(
+            s.register(0, RtParams::new(5, 4)),
+            Err(AdmissionError::InvalidParams)
+        );
+        assert!(s.check_schedulable().response_times.is_empty());
+    }
+}
@@ -1,7 +1,8 @@
 use super::*;
 
-pub struct RRScheduler {
+pub struct RRScheduler<I: Instrument = NoInstrument> {
     inner: Mutex<RRSchedulerInner>,
+    instrument: I,
 }
 
 struct RRSchedulerInner {
@@ -13,34 +14,55 @@ struct RRSchedulerInner {
 struct RRProcInfo {
     present: bool,
     rest_slice: usize,
+    affinity: CpuMask,
     prev: Tid,
     next: Tid,
 }
 
-impl Scheduler for RRScheduler {
+impl<I: Instrument> Scheduler for RRScheduler<I> {
     fn push(&self, tid: usize) {
         self.inner.lock().push(tid);
+        self.instrument.on_push(tid);
+        platform::wake_idle_cpu();
     }
-    fn pop(&self, _cpu_id: usize) -> Option<usize> {
-        self.inner.lock().pop()
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.inner.lock().pop(cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
     }
     fn tick(&self, current_tid: usize) -> bool {
-        self.inner.lock().tick(current_tid)
+        let need_reschedule = self.inner.lock().tick(current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
     }
     fn set_priority(&self, _tid: usize, _priority: u8) {}
     fn remove(&self, tid: usize) {
         self.inner.lock().remove(tid)
     }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.inner.lock().set_affinity(tid, mask);
+    }
 }
 
 impl RRScheduler {
     pub fn new(max_time_slice: usize) -> Self {
+        Self::with_instrument(max_time_slice, NoInstrument)
+    }
+}
+
+impl<I: Instrument> RRScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    pub fn with_instrument(max_time_slice: usize, instrument: I) -> Self {
         let inner = RRSchedulerInner {
             max_time_slice,
             infos: Vec::default(),
         };
         RRScheduler {
             inner: Mutex::new(inner),
+            instrument,
         }
     }
 }
@@ -61,8 +83,13 @@ impl RRSchedulerInner {
         trace!("rr push {}", tid - 1);
     }
 
-    fn pop(&mut self) -> Option<Tid> {
-        let ret = match self.infos[0].next {
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        // the first thread allowed on this CPU
+        let mut tid = self.infos[0].next;
+        while tid != 0 && !self.infos[tid].affinity.contains(cpu_id) {
+            tid = self.infos[tid].next;
+        }
+        let ret = match tid {
             0 => None,
             tid => {
                 self.infos[tid].present = false;
@@ -92,6 +119,11 @@ impl RRSchedulerInner {
         self._list_remove(tid + 1);
         self.infos[tid + 1].present = false;
     }
+
+    fn set_affinity(&mut self, tid: Tid, mask: CpuMask) {
+        expand(&mut self.infos, tid + 1);
+        self.infos[tid + 1].affinity = mask;
+    }
 }
 
 impl RRSchedulerInner {
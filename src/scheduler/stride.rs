@@ -7,14 +7,17 @@
 use super::*;
 use core::cmp::{Ordering, Reverse};
 
-pub struct StrideScheduler {
+pub struct StrideScheduler<I: Instrument = NoInstrument> {
     inner: Mutex<StrideSchedulerInner>,
+    instrument: I,
 }
 
 pub struct StrideSchedulerInner {
     max_time_slice: usize,
     infos: Vec<StrideProcInfo>,
     queue: BinaryHeap<Reverse<(Stride, Tid)>>, // It's max heap, so use Reverse
+    /// Stride of the last task selected, if tasks pushed behind it catch up.
+    min_stride: Option<Stride>,
 }
 
 #[derive(Debug, Default, Copy, Clone)]
@@ -23,6 +26,7 @@ struct StrideProcInfo {
     rest_slice: usize,
     stride: Stride,
     priority: u8,
+    affinity: CpuMask,
 }
 
 const BIG_STRIDE: Stride = Stride(0x7FFFFFFF);
@@ -62,15 +66,24 @@ impl StrideProcInfo {
     }
 }
 
-impl Scheduler for StrideScheduler {
+impl<I: Instrument> Scheduler for StrideScheduler<I> {
     fn push(&self, tid: usize) {
         self.inner.lock().push(tid);
+        self.instrument.on_push(tid);
+        platform::wake_idle_cpu();
     }
-    fn pop(&self, _cpu_id: usize) -> Option<usize> {
-        self.inner.lock().pop()
+    fn pop(&self, cpu_id: usize) -> Option<usize> {
+        let ret = self.inner.lock().pop(cpu_id);
+        if let Some(tid) = ret {
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
+        }
+        ret
     }
     fn tick(&self, current_tid: usize) -> bool {
-        self.inner.lock().tick(current_tid)
+        let need_reschedule = self.inner.lock().tick(current_tid);
+        self.instrument.on_tick(current_tid, need_reschedule);
+        need_reschedule
     }
     fn set_priority(&self, tid: usize, priority: u8) {
         self.inner.lock().set_priority(tid, priority);
@@ -78,19 +91,41 @@ impl Scheduler for StrideScheduler {
     fn remove(&self, tid: usize) {
         self.inner.lock().remove(tid);
     }
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        self.inner.lock().set_affinity(tid, mask);
+    }
 }
 
 impl StrideScheduler {
     pub fn new(max_time_slice: usize) -> Self {
+        Self::with_instrument(max_time_slice, NoInstrument)
+    }
+}
+
+impl<I: Instrument> StrideScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    pub fn with_instrument(max_time_slice: usize, instrument: I) -> Self {
         let inner = StrideSchedulerInner {
             max_time_slice,
             infos: Vec::default(),
             queue: BinaryHeap::default(),
+            min_stride: None,
         };
         StrideScheduler {
             inner: Mutex::new(inner),
+            instrument,
         }
     }
+
+    /// Let a task pushed with a stride behind the one of the last task selected start
+    /// from there, instead of running ahead of everyone.
+    ///
+    /// Meant for the instances of a [`PerCpuScheduler`], where tasks come from other CPUs
+    /// with strides unrelated to the ones here.
+    pub fn catch_up(self) -> Self {
+        self.inner.lock().min_stride = Some(Stride::default());
+        self
+    }
 }
 
 impl StrideSchedulerInner {
@@ -101,18 +136,32 @@ impl StrideSchedulerInner {
         if info.rest_slice == 0 {
             info.rest_slice = self.max_time_slice;
         }
+        if let Some(min_stride) = self.min_stride {
+            info.stride = info.stride.max(min_stride);
+        }
         self.queue.push(Reverse((info.stride, tid)));
         trace!("stride push {}", tid);
     }
 
-    fn pop(&mut self) -> Option<Tid> {
-        let ret = self.queue.pop().map(|Reverse((_, tid))| tid);
+    fn pop(&mut self, cpu_id: usize) -> Option<Tid> {
+        // set aside the threads not allowed on this CPU
+        let mut skipped = Vec::new();
+        let ret = loop {
+            match self.queue.pop() {
+                Some(Reverse((_, tid))) if !self.infos[tid].present => {}
+                Some(entry) if !self.infos[(entry.0).1].affinity.contains(cpu_id) => {
+                    skipped.push(entry)
+                }
+                entry => break entry.map(|Reverse((_, tid))| tid),
+            }
+        };
+        self.queue.extend(skipped);
         if let Some(tid) = ret {
             let info = &mut self.infos[tid];
-            if !info.present {
-                return self.pop();
-            }
             let old_stride = info.stride;
+            if let Some(min_stride) = &mut self.min_stride {
+                *min_stride = (*min_stride).max(old_stride);
+            }
             info.pass();
             let stride = info.stride;
             info.present = false;
@@ -143,4 +192,42 @@ impl StrideSchedulerInner {
     fn remove(&mut self, tid: Tid) {
         self.infos[tid].present = false;
     }
+
+    fn set_affinity(&mut self, tid: Tid, mask: CpuMask) {
+        expand(&mut self.infos, tid);
+        self.infos[tid].affinity = mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run 0 alone for a while, then let 1 in, and tell who runs next.
+    fn newcomer_runs(s: StrideScheduler) -> Vec<Tid> {
+        s.push(0);
+        s.set_priority(0, 100);
+        for _ in 0..10 {
+            assert_eq!(s.pop(0), Some(0));
+            s.push(0);
+        }
+        s.push(1);
+        s.set_priority(1, 100);
+        (0..4)
+            .map(|_| {
+                let tid = s.pop(0).unwrap();
+                s.push(tid);
+                tid
+            })
+            .collect()
+    }
+
+    #[test]
+    fn newcomers_catch_up_if_asked() {
+        assert_eq!(newcomer_runs(StrideScheduler::new(5)), [1, 1, 1, 1]);
+        assert_eq!(
+            newcomer_runs(StrideScheduler::new(5).catch_up()),
+            [1, 0, 1, 0]
+        );
+    }
 }
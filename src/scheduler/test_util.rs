@@ -0,0 +1,49 @@
+//! Running threads through a scheduler in unit tests.
+
+use super::Scheduler;
+use crate::asynchronous::stats::Clock;
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A clock moved by hand.
+#[derive(Default)]
+pub struct TestClock(AtomicU64);
+
+impl TestClock {
+    pub fn leak() -> &'static TestClock {
+        Box::leak(Box::new(TestClock::default()))
+    }
+
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::Relaxed);
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Run `n` always-ready threads on CPU 0 for `ticks`, and count the ticks each got.
+pub fn share(s: &dyn Scheduler, n: usize, ticks: usize) -> Vec<usize> {
+    let mut runs = vec![0; n];
+    for tid in 0..n {
+        s.push(tid);
+    }
+    let mut current = s.pop(0).unwrap();
+    for _ in 0..ticks {
+        runs[current] += 1;
+        if s.tick(current) {
+            s.push(current);
+            current = s.pop(0).unwrap();
+        }
+    }
+    runs
+}
+
+/// Whether `a / b` is within `tolerance` of `ratio`.
+pub fn about(a: usize, b: usize, ratio: f64, tolerance: f64) -> bool {
+    let actual = a as f64 / b as f64;
+    (actual - ratio).abs() <= ratio * tolerance
+}
@@ -2,32 +2,94 @@
 //!
 //! Each CPU has its own queue, and each CPU takes new jobs from its own queue.
 //! When its queue is empty, steal jobs from other CPU's queue.
+//! A job is only ever queued on, and stolen by, CPUs of its affinity.
+//!
+//! Only its CPU pushes to and pops from its own queue. Jobs queued from other CPUs,
+//! or found on a CPU they are not allowed on, go through a locked inbox of their new CPU,
+//! emptied into its queue on its next pop, and stolen from like the queue.
 
 use super::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use deque::{self, Stealer, Stolen, Worker};
+use spin::RwLock;
 
-pub struct WorkStealingScheduler {
+pub struct WorkStealingScheduler<I: Instrument = NoInstrument> {
     /// The ready queue of each processors
     workers: Vec<Worker<Tid>>,
     /// Stealers to all processors' queue
     stealers: Vec<Stealer<Tid>>,
+    /// Jobs queued on each processor by the others
+    inboxes: Vec<Mutex<Vec<Tid>>>,
+    /// The affinity of each thread, growing as threads get one.
+    affinities: RwLock<Vec<AtomicUsize>>,
+    instrument: I,
 }
 
 impl WorkStealingScheduler {
     pub fn new(core_num: usize) -> Self {
+        Self::with_instrument(core_num, NoInstrument)
+    }
+}
+
+impl<I: Instrument> WorkStealingScheduler<I> {
+    /// Create a scheduler reporting its events to `instrument`.
+    pub fn with_instrument(core_num: usize, instrument: I) -> Self {
         let (workers, stealers) = (0..core_num).map(|_| deque::new()).unzip();
-        WorkStealingScheduler { workers, stealers }
+        let inboxes = (0..core_num).map(|_| Mutex::new(Vec::new())).collect();
+        WorkStealingScheduler {
+            workers,
+            stealers,
+            inboxes,
+            affinities: RwLock::new(Vec::new()),
+            instrument,
+        }
+    }
+
+    fn affinity(&self, tid: Tid) -> CpuMask {
+        let affinities = self.affinities.read();
+        affinities
+            .get(tid)
+            .map_or(CpuMask::ALL, |mask| CpuMask(mask.load(Ordering::Relaxed)))
+    }
+
+    /// Queue `tid` on `cpu` if allowed, otherwise on the next allowed CPU after it.
+    /// Return the CPU chosen.
+    fn push_near(&self, tid: Tid, cpu: usize) -> usize {
+        let n = self.workers.len();
+        let affinity = self.affinity(tid);
+        let cpu = (0..n)
+            .map(|i| (cpu + i) % n)
+            .find(|&cpu| affinity.contains(cpu))
+            .unwrap_or_else(|| {
+                warn!("work-stealing: thread {} allowed on no CPU", tid);
+                cpu
+            });
+        if cpu == platform::cpu_id() {
+            self.workers[cpu].push(tid);
+        } else {
+            self.inboxes[cpu].lock().push(tid);
+        }
+        cpu
+    }
+
+    /// Take a job allowed on `cpu_id` out of the inbox of `other_id`.
+    fn steal_inbox(&self, cpu_id: usize, other_id: usize) -> Option<Tid> {
+        let mut inbox = self.inboxes[other_id].lock();
+        let i = inbox
+            .iter()
+            .position(|&tid| self.affinity(tid).contains(cpu_id))?;
+        Some(inbox.remove(i))
     }
 }
 
-impl Scheduler for WorkStealingScheduler {
+impl<I: Instrument> Scheduler for WorkStealingScheduler<I> {
     fn push(&self, tid: usize) {
         // not random, but uniform
         // no sync, because we don't need to
         static mut WORKER_CPU: usize = 0;
         let n = self.workers.len();
         let mut cpu = unsafe {
-            WORKER_CPU = WORKER_CPU + 1;
+            WORKER_CPU += 1;
             if WORKER_CPU >= n {
                 WORKER_CPU -= n;
             }
@@ -38,13 +100,36 @@ impl Scheduler for WorkStealingScheduler {
         if cpu >= n {
             cpu -= n;
         }
-        self.workers[cpu].push(tid);
+        let cpu = self.push_near(tid, cpu);
         trace!("work-stealing: cpu{} push thread {}", cpu, tid);
+        self.instrument.on_push(tid);
+        // if its CPU is a busy remote one, an idle one can steal it
+        if cpu != platform::cpu_id() && !platform::wake_cpu(cpu) {
+            platform::wake_idle_cpu();
+        }
     }
 
     fn pop(&self, cpu_id: usize) -> Option<usize> {
-        if let Some(tid) = self.workers[cpu_id].pop() {
+        let inbox = core::mem::take(&mut *self.inboxes[cpu_id].lock());
+        for tid in inbox {
+            self.workers[cpu_id].push(tid);
+        }
+        // threads whose affinity changed since they were queued are moved once we are done,
+        // so that each is met at most once, even if it is allowed nowhere and comes back here
+        let mut moved = Vec::new();
+        let local = loop {
+            match self.workers[cpu_id].pop() {
+                Some(tid) if !self.affinity(tid).contains(cpu_id) => moved.push(tid),
+                local => break local,
+            }
+        };
+        for tid in moved {
+            self.push_near(tid, cpu_id);
+        }
+        if let Some(tid) = local {
             trace!("work-stealing: cpu{} pop thread {}", cpu_id, tid);
+            self.instrument.on_pop(tid);
+            self.instrument.on_switch(cpu_id, tid);
             return Some(tid);
         }
         let n = self.workers.len();
@@ -56,7 +141,25 @@ impl Scheduler for WorkStealingScheduler {
             loop {
                 match self.stealers[other_id].steal() {
                     Stolen::Abort => {} // retry
-                    Stolen::Empty => break,
+                    Stolen::Empty => match self.steal_inbox(cpu_id, other_id) {
+                        Some(tid) => {
+                            trace!(
+                                "work-stealing: cpu{} steal thread {} from the inbox of cpu{}",
+                                cpu_id,
+                                tid,
+                                other_id
+                            );
+                            self.instrument.on_pop(tid);
+                            self.instrument.on_switch(cpu_id, tid);
+                            return Some(tid);
+                        }
+                        None => break,
+                    },
+                    Stolen::Data(tid) if !self.affinity(tid).contains(cpu_id) => {
+                        // not ours to run, give it back and try the next victim
+                        self.push_near(tid, other_id);
+                        break;
+                    }
                     Stolen::Data(tid) => {
                         trace!(
                             "work-stealing: cpu{} steal thread {} from cpu{}",
@@ -64,6 +167,8 @@ impl Scheduler for WorkStealingScheduler {
                             tid,
                             other_id
                         );
+                        self.instrument.on_pop(tid);
+                        self.instrument.on_switch(cpu_id, tid);
                         return Some(tid);
                     }
                 }
@@ -72,11 +177,86 @@ impl Scheduler for WorkStealingScheduler {
         None
     }
 
-    fn tick(&self, _current_tid: usize) -> bool {
+    fn tick(&self, current_tid: usize) -> bool {
+        self.instrument.on_tick(current_tid, true);
         true
     }
 
     fn set_priority(&self, _tid: usize, _priority: u8) {}
 
     fn remove(&self, _tid: usize) {}
+
+    fn set_affinity(&self, tid: usize, mask: CpuMask) {
+        if let Some(slot) = self.affinities.read().get(tid) {
+            slot.store(mask.0, Ordering::Relaxed);
+            return;
+        }
+        let mut affinities = self.affinities.write();
+        let len = affinities.len();
+        affinities.extend((len..=tid).map(|_| AtomicUsize::new(CpuMask::ALL.0)));
+        affinities[tid].store(mask.0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(s: &WorkStealingScheduler, cpu: usize) -> Vec<Tid> {
+        let mut tids: Vec<Tid> = core::iter::from_fn(|| s.pop(cpu)).collect();
+        tids.sort_unstable();
+        tids
+    }
+
+    #[test]
+    fn idle_cpu_steals_everything() {
+        let s = WorkStealingScheduler::new(3);
+        for tid in 0..6 {
+            s.push(tid);
+        }
+        assert_eq!(drain(&s, 2), [0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn threads_run_only_where_allowed() {
+        let s = WorkStealingScheduler::new(2);
+        s.set_affinity(0, CpuMask::single(1));
+        for tid in 0..4 {
+            s.push(tid);
+        }
+        assert_eq!(drain(&s, 0), [1, 2, 3]);
+        assert_eq!(drain(&s, 1), [0]);
+    }
+
+    #[test]
+    fn misplaced_thread_goes_to_an_inbox() {
+        let s = WorkStealingScheduler::new(2);
+        s.push(0);
+        // queued before its affinity changed, wherever it is CPU 0 passes it on
+        s.set_affinity(0, CpuMask::single(1));
+        assert_eq!(s.pop(0), None);
+        assert_eq!(*s.inboxes[1].lock(), [0]);
+        assert_eq!(s.pop(1), Some(0));
+        assert!(s.inboxes[1].lock().is_empty());
+    }
+
+    #[test]
+    fn inboxes_are_stolen_from() {
+        let s = WorkStealingScheduler::new(3);
+        s.set_affinity(0, CpuMask(0b110));
+        // queued on CPU 1 by another, which has not popped since
+        s.inboxes[1].lock().push(0);
+        assert_eq!(drain(&s, 0), []);
+        assert_eq!(drain(&s, 2), [0]);
+    }
+
+    #[test]
+    fn affinities_grow_with_thread_ids() {
+        let s = WorkStealingScheduler::new(2);
+        s.set_affinity(1000, CpuMask::single(1));
+        s.push(1000);
+        s.push(2000);
+        assert_eq!(drain(&s, 0), [2000]);
+        assert_eq!(drain(&s, 1), [1000]);
+    }
 }